@@ -0,0 +1,166 @@
+use std::collections::HashSet;
+
+use super::state::State;
+use super::transaction::Transaction;
+use super::verifier::SigCache;
+use crate::types::{address::Address, hash::Hash};
+
+pub const DEFAULT_MAX_BLOCK_TRANSACTIONS: usize = 1000;
+pub const DEFAULT_MAX_BLOCK_BYTES: usize = 1024 * 1024;
+
+// how much a produced block may hold, the bytes count encoded transactions
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BlockLimits {
+    pub max_transactions: usize,
+    pub max_bytes: usize,
+}
+
+impl Default for BlockLimits {
+    fn default() -> Self {
+        BlockLimits {
+            max_transactions: DEFAULT_MAX_BLOCK_TRANSACTIONS,
+            max_bytes: DEFAULT_MAX_BLOCK_BYTES,
+        }
+    }
+}
+
+// the transactions picked for a new block, and the candidates that can't be
+// executed on top of the state or have an invalid signature
+#[derive(Debug, Default)]
+pub struct Selection {
+    pub transactions: Vec<Transaction>,
+    pub invalid: Vec<Hash>,
+}
+
+// picks the transactions for a new block from the candidates, in order, until
+// the limits are filled, so the block is always valid. Candidates are only
+// taken as needed. Once one of a sender's transactions is left out, the
+// sender's later ones are skipped, as they can't execute without it
+pub fn select_transactions<'a>(
+    candidates: impl IntoIterator<Item = &'a Transaction>,
+    state: &State,
+    sig_cache: &SigCache,
+    chain_id: u32,
+    limits: &BlockLimits,
+) -> Selection {
+    let mut state = state.clone();
+    let mut selection = Selection::default();
+    let mut skipped: HashSet<Address> = HashSet::new();
+    let mut bytes = 0;
+    for tx in candidates {
+        if selection.transactions.len() >= limits.max_transactions {
+            break;
+        }
+        let mut tx = tx.clone();
+        let sender = match tx.sender() {
+            Ok(sender) => sender,
+            Err(_) => {
+                selection.invalid.push(tx.hash());
+                continue;
+            }
+        };
+        if skipped.contains(&sender) {
+            continue;
+        }
+        let size = tx.encode().len();
+        if bytes + size > limits.max_bytes {
+            // a smaller one may still fit
+            skipped.insert(sender);
+            continue;
+        }
+        if sig_cache.verify(&tx, chain_id).is_err()
+            || state.apply_transactions(std::slice::from_ref(&tx)).is_err()
+        {
+            skipped.insert(sender);
+            selection.invalid.push(tx.hash());
+            continue;
+        }
+        bytes += size;
+        selection.transactions.push(tx);
+    }
+    selection
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::keypair::KeyPair;
+
+    fn signed_transaction(amount: u64, nonce: u64) -> Transaction {
+        let mut tx = Transaction::new([0; 20], amount, nonce, 0);
+        tx.sign(&KeyPair::new(0));
+        tx
+    }
+
+    fn signed_by(key: u64, amount: u64, nonce: u64) -> Transaction {
+        let mut tx = Transaction::new([0; 20], amount, nonce, 0);
+        tx.sign(&KeyPair::new(key));
+        tx
+    }
+
+    #[test]
+    fn test_select_transactions() {
+        let mut state = State::new();
+        state.credit(KeyPair::new(0).address(), 10);
+        state.credit(KeyPair::new(2).address(), 10);
+        let cache = SigCache::new(100);
+        let limits = BlockLimits::default();
+
+        let mut bad_signature = signed_by(2, 1, 0);
+        bad_signature.signature = Some("00".repeat(65));
+        let mut unaffordable = signed_by(1, 5, 0);
+        let candidates = vec![
+            unaffordable.clone(),
+            signed_transaction(5, 0),
+            // can't execute after the sender's unaffordable one
+            signed_by(1, 0, 1),
+            bad_signature.clone(),
+            signed_transaction(5, 1),
+            // overdraws the sender
+            signed_transaction(5, 2),
+        ];
+        let mut selection = select_transactions(&candidates, &state, &cache, 0, &limits);
+        let nonces: Vec<u64> = selection
+            .transactions
+            .iter()
+            .map(|tx| tx.data.nonce)
+            .collect();
+        assert_eq!(nonces, vec![0, 1]);
+        assert_eq!(
+            selection.transactions[1].hash(),
+            signed_transaction(5, 1).hash()
+        );
+        assert_eq!(
+            selection.invalid,
+            vec![
+                unaffordable.hash(),
+                bad_signature.hash(),
+                signed_transaction(5, 2).hash()
+            ]
+        );
+    }
+
+    #[test]
+    fn test_limits() {
+        let mut state = State::new();
+        state.credit(KeyPair::new(0).address(), 100);
+        let cache = SigCache::new(100);
+        let candidates: Vec<Transaction> = (0..5).map(|n| signed_transaction(1, n)).collect();
+
+        let by_count = BlockLimits {
+            max_transactions: 3,
+            ..BlockLimits::default()
+        };
+        let selection = select_transactions(&candidates, &state, &cache, 0, &by_count);
+        assert_eq!(selection.transactions.len(), 3);
+
+        let size = candidates[0].encode().len();
+        let by_bytes = BlockLimits {
+            max_bytes: size * 2 + 1,
+            ..BlockLimits::default()
+        };
+        let selection = select_transactions(&candidates, &state, &cache, 0, &by_bytes);
+        assert_eq!(selection.transactions.len(), 2);
+        assert!(selection.invalid.is_empty());
+    }
+}
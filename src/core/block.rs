@@ -1,23 +1,33 @@
+use super::hasher::{merkle_proof, merkle_root, MerkleProof};
 use super::transaction::Transaction;
-use crate::{crypto::keypair::KeyPair, types::hash::Hash};
+use super::verifier::{verify_transactions, SigCache};
+#[cfg(test)]
+use crate::crypto::keypair::KeyPair;
+use crate::crypto::scheme::{Scheme, Signer};
+use crate::types::{address::Address, hash::Hash};
 use serde::{Deserialize, Serialize};
-use sha256::{digest, try_digest};
 use std::time::{SystemTime, UNIX_EPOCH};
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct Header {
     pub version: u32,
+    // identifies the network this block belongs to
+    pub chain_id: u32,
     pub data_hash: Hash,
     pub timestamp: i64,
     pub prev_block_hash: Hash,
     pub height: u32,
-    #[serde(skip_serializing)]
+    // the address of the key that signed the block, zero for the genesis block
+    #[serde(default)]
+    pub validator: Address,
+    #[serde(skip)]
     hash: Option<Hash>,
 }
 
 impl Header {
     pub fn new(
         version: u32,
+        chain_id: u32,
         data_hash: Hash,
         prev_block_hash: Hash,
         timestamp: i64,
@@ -25,10 +35,12 @@ impl Header {
     ) -> Self {
         Self {
             version,
+            chain_id,
             data_hash,
             timestamp,
             prev_block_hash,
             height,
+            validator: [0; 20],
             hash: None,
         }
     }
@@ -38,17 +50,29 @@ impl Header {
     }
 
     pub fn hash(&mut self) -> Hash {
-        if self.hash.clone() == None {
-            self.hash = Some(digest(self.encode()));
+        if self.hash.is_none() {
+            self.hash = Some(Hash::digest(self.encode().as_bytes()));
         }
-        self.hash.clone().unwrap()
+        self.hash.unwrap()
     }
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+// the validator's signature of the header hash
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct BlockSignature {
+    pub scheme: Scheme,
+    pub signature: String,
+    // only needed for signatures the key can't be recovered from
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub public_key: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct Block {
     pub header: Header,
     pub transactions: Vec<Transaction>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub signature: Option<BlockSignature>,
 }
 
 pub fn new_block_from_prev_header(
@@ -60,28 +84,29 @@ pub fn new_block_from_prev_header(
     let since = start.duration_since(UNIX_EPOCH).expect("time error");
     let header = Header {
         version: 0,
+        chain_id: prev_header.chain_id,
         data_hash,
         timestamp: since.as_millis() as i64,
         height: prev_header.height + 1,
         prev_block_hash: prev_header.hash(),
+        validator: [0; 20],
         hash: None,
     };
-    return Block::new(header, transactions);
+    Block::new(header, transactions)
 }
 
-pub fn calculate_data_hash(transactions: &mut Vec<Transaction>) -> Hash {
-    let mut result = "".to_string();
-    for t in transactions {
-        result += &t.hash();
-    }
-    digest(result)
+// the data hash is the merkle root of the transaction hashes
+pub fn calculate_data_hash(transactions: &mut [Transaction]) -> Hash {
+    let hashes: Vec<Hash> = transactions.iter_mut().map(|t| t.hash()).collect();
+    merkle_root(&hashes)
 }
 
 impl Block {
     pub fn new(header: Header, transactions: Vec<Transaction>) -> Self {
         Self {
-            header: header,
-            transactions: transactions,
+            header,
+            transactions,
+            signature: None,
         }
     }
 
@@ -93,61 +118,183 @@ impl Block {
         self.header.hash()
     }
 
-    pub fn verify(&mut self) -> Result<(), String> {
-        for t in &self.transactions {
-            t.verify()?;
+    // commits the header to the signer's address and signs its hash
+    pub fn sign(&mut self, signer: &dyn Signer) {
+        self.header.validator = signer.address();
+        self.header.hash = None;
+        let signed = signer.sign(&self.hash().0);
+        self.signature = Some(BlockSignature {
+            scheme: signer.scheme(),
+            signature: signed.signature,
+            public_key: signed.public_key,
+        });
+    }
+
+    // every block but genesis carries the signature of the validator it names
+    pub fn verify_signature(&mut self) -> Result<(), String> {
+        let hash = self.hash();
+        let signature = match &self.signature {
+            Some(signature) => signature,
+            None if self.header.height == 0 => return Ok(()),
+            None => return Err(format!("block {} is not signed", hash)),
+        };
+        let signer = signature.scheme.verifier().verify(
+            &hash.0,
+            &signature.signature,
+            signature.public_key.as_deref(),
+        )?;
+        if signer != self.header.validator {
+            return Err(format!("block {} is not signed by its validator", hash));
         }
-        // verify data hash matches
+        Ok(())
+    }
+
+    // proves the transaction is committed to by header.data_hash
+    pub fn merkle_proof(&mut self, tx_hash: &Hash) -> Option<MerkleProof> {
+        let hashes: Vec<Hash> = self.transactions.iter_mut().map(|t| t.hash()).collect();
+        merkle_proof(&hashes, tx_hash)
+    }
+
+    pub fn verify(&mut self) -> Result<(), String> {
+        self.verify_with(None)
+    }
+
+    // skips signatures already in the cache and adds the ones it checks
+    pub fn verify_with_cache(&mut self, cache: &SigCache) -> Result<(), String> {
+        self.verify_with(Some(cache))
+    }
+
+    fn verify_with(&mut self, cache: Option<&SigCache>) -> Result<(), String> {
+        // the data hash is cheap to check, so do it before the signatures
         let data_hash = calculate_data_hash(&mut self.transactions);
         if data_hash != self.header.data_hash {
             return Err(format!("block {} has an invalid data hash", self.hash()));
         }
-        Ok(())
+        self.verify_signature()?;
+        verify_transactions(&self.transactions, self.header.chain_id, cache)
+            .map_err(|e| format!("block {} has an invalid {}", self.hash(), e))
     }
 }
 
+// the transaction in a block at height h carries nonce h - 1, so consecutive
+// random blocks from KeyPair::new(0) form a valid nonce sequence
+#[cfg(test)]
+pub fn random_block(height: u32, prev_hash: Hash) -> Block {
+    let mut tx = Transaction::new([0; 20], 5, height.saturating_sub(1) as u64, 0);
+    let key_pair = KeyPair::new(0);
+    tx.sign(&key_pair);
+    let header = Header::new(0, 0, Hash::zero(), prev_hash, 0, height);
+    let mut b = Block::new(header, vec![tx]);
+    b.header.data_hash = calculate_data_hash(&mut b.transactions);
+    b.sign(&key_pair);
+    b
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::core::hasher::verify_merkle_proof;
 
     #[test]
     fn test_encode_decode_block() {
-        let mut b = random_block(0, "".to_string());
+        let mut b = random_block(0, Hash::zero());
 
         let data = b.encode();
         println!("{}", data);
         let mut b_decode: Block = serde_json::from_str(&data).unwrap();
 
-        assert_eq!(b.hash() == b_decode.hash(), true);
+        assert_eq!(b.hash(), b_decode.hash());
+    }
+
+    #[test]
+    fn test_decode_ignores_forged_hashes() {
+        let mut b = random_block(1, Hash::zero());
+        let hash = b.hash();
+        let tx_hash = b.transactions[0].hash();
+
+        let mut json: serde_json::Value = serde_json::from_str(&b.encode()).unwrap();
+        let forged = serde_json::to_value(Hash::zero()).unwrap();
+        json["header"]["hash"] = forged.clone();
+        json["transactions"][0]["hash"] = forged;
+        let mut decoded: Block = serde_json::from_value(json).unwrap();
+        assert_eq!(decoded.hash(), hash);
+        assert_eq!(decoded.transactions[0].hash(), tx_hash);
     }
 
     #[test]
     fn test_verify_block() {
-        let mut b = random_block(0, "".to_string());
+        let mut b = random_block(0, Hash::zero());
 
-        assert_eq!(b.verify().is_ok(), true);
+        assert!(b.verify().is_ok());
 
-        let other_tx = Transaction::new([0; 20], 5);
+        let other_tx = Transaction::new([0; 20], 5, 0, 0);
         // don't sign transaction
         b.transactions.push(other_tx);
 
-        assert_eq!(b.verify().is_ok(), false);
+        assert!(b.verify().is_err());
 
         // remove transaction
         b.transactions.pop();
-        assert_eq!(b.verify().is_ok(), true);
+        assert!(b.verify().is_ok());
 
-        b.header.data_hash = "invalid hash".to_string();
-        assert_eq!(b.verify().is_ok(), false);
+        b.header.data_hash = Hash::digest(b"invalid hash");
+        assert!(b.verify().is_err());
     }
-}
 
-pub fn random_block(height: u32, prev_hash: Hash) -> Block {
-    let mut tx = Transaction::new([0; 20], 5);
-    let key_pair = KeyPair::new(0);
-    tx.sign(&key_pair);
-    let header = Header::new(0, "".to_string(), prev_hash, 0, 0);
-    let mut b = Block::new(header, vec![tx]);
-    b.header.data_hash = calculate_data_hash(&mut b.transactions);
-    b
+    #[test]
+    fn test_merkle_proof() {
+        let mut b = random_block(0, Hash::zero());
+        let mut other_tx = Transaction::new([1; 20], 5, 1, 0);
+        other_tx.sign(&KeyPair::new(0));
+        let tx_hash = other_tx.hash();
+        b.transactions.push(other_tx);
+        b.header.data_hash = calculate_data_hash(&mut b.transactions);
+
+        let proof = b.merkle_proof(&tx_hash).unwrap();
+        assert!(verify_merkle_proof(&b.header.data_hash, &proof));
+        assert!(b.merkle_proof(&Hash::digest(b"missing")).is_none());
+    }
+
+    #[test]
+    fn test_verify_block_rejects_other_chain_transaction() {
+        let mut b = random_block(0, Hash::zero());
+
+        let mut other_tx = Transaction::new([0; 20], 5, 1, 1);
+        other_tx.sign(&KeyPair::new(0));
+        b.transactions.push(other_tx);
+        b.header.data_hash = calculate_data_hash(&mut b.transactions);
+
+        assert!(b.verify().is_err());
+    }
+
+    #[test]
+    fn test_sign_block() {
+        let key_pair = KeyPair::new(1);
+        let mut b = random_block(1, Hash::zero());
+        b.sign(&key_pair);
+        assert_eq!(b.header.validator, key_pair.address());
+        let mut decoded: Block = serde_json::from_str(&b.encode()).unwrap();
+        assert!(decoded.verify().is_ok());
+
+        let mut unsigned = decoded.clone();
+        unsigned.signature = None;
+        assert!(unsigned.verify().is_err());
+
+        // only the genesis block is unsigned
+        let mut genesis = random_block(0, Hash::zero());
+        genesis.signature = None;
+        genesis.header.validator = [0; 20];
+        genesis.header.hash = None;
+        assert!(genesis.verify().is_ok());
+
+        // the signature can't be moved to a block naming another validator
+        let mut other = decoded.clone();
+        other.header.validator = KeyPair::new(2).address();
+        other.header.hash = None;
+        assert!(other.verify().is_err());
+
+        let mut ed25519 = random_block(1, Hash::zero());
+        ed25519.sign(&crate::crypto::ed25519::Ed25519KeyPair::new(0));
+        assert!(ed25519.verify().is_ok());
+    }
 }
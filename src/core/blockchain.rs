@@ -1,92 +1,545 @@
 use super::block::*;
+use super::forkchoice::{ForkChoice, LongestChain};
+use super::hasher::MerkleProof;
+use super::state::{State, StateUndo};
+use super::storage::{BlockStore, MemoryBlockStore};
+use super::transaction::Transaction;
+use super::verifier::{SigCache, DEFAULT_SIG_CACHE_SIZE};
+use crate::types::address::{encode_address, Address};
 use crate::types::hash::Hash;
-use std::borrow::BorrowMut;
-use std::collections::HashMap;
-use std::sync::RwLock;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+
+// main chain blocks this far below the head are final, no side branch may replace them
+pub const FINALITY_DEPTH: u32 = 100;
+// how many side branch blocks are kept, the lightest branch tips are dropped beyond it
+pub const MAX_SIDE_BLOCKS: usize = 1000;
 
-#[derive(Debug)]
 pub struct Blockchain {
-    blocks: Vec<Block>,
+    // the main chain, by height
+    store: Box<dyn BlockStore>,
+    state: State,
+    // undo_log[h - 1] reverts the state changes made by the main chain block at height h
+    undo_log: Vec<StateUndo>,
+    // taken from the genesis header, every block must carry the same id
+    chain_id: u32,
+    // block hash => height
+    block_index: HashMap<Hash, u32>,
+    // transaction hash => (height of the including block, position in the block)
+    tx_index: HashMap<Hash, (u32, usize)>,
+    // blocks with a known parent that are not part of the main chain, forking
+    // above the final height and at most max_side_blocks of them
+    side_blocks: HashMap<Hash, Block>,
+    finality_depth: u32,
+    max_side_blocks: usize,
+    fork_choice: Box<dyn ForkChoice>,
+    // block hash => total fork choice weight from genesis, for main chain and side blocks.
+    // Entries of dropped side blocks are removed with them
+    weights: HashMap<Hash, u64>,
+    // transactions that were in blocks removed by a reorg but are not in the new main chain
+    orphaned_transactions: Vec<Transaction>,
+    // signatures already checked, shared with the mempool
+    sig_cache: SigCache,
+    // the keys allowed to sign blocks, no block but genesis is accepted while it is empty
+    validators: HashSet<Address>,
+}
+
+impl fmt::Debug for Blockchain {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Blockchain")
+            .field("chain_id", &self.chain_id)
+            .field("height", &self.height())
+            .field("side_blocks", &self.side_blocks.len())
+            .field("state", &self.state)
+            .finish()
+    }
 }
 
 impl Blockchain {
     pub fn new(genesis: Block) -> Self {
-        let bc = Blockchain {
-            blocks: vec![genesis],
+        Self::new_with_state(genesis, State::new())
+    }
+
+    // the given state holds the genesis allocations; transactions in the genesis block are not executed
+    pub fn new_with_state(genesis: Block, state: State) -> Self {
+        Self::open(genesis, state, Box::new(MemoryBlockStore::new())).unwrap()
+    }
+
+    pub fn open(genesis: Block, state: State, store: Box<dyn BlockStore>) -> Result<Self, String> {
+        Self::open_with_fork_choice(genesis, state, store, Box::new(LongestChain))
+    }
+
+    // an empty store is initialised with the genesis block, otherwise the stored
+    // chain must start from the same genesis and its blocks are replayed to rebuild the state
+    pub fn open_with_fork_choice(
+        mut genesis: Block,
+        state: State,
+        mut store: Box<dyn BlockStore>,
+        fork_choice: Box<dyn ForkChoice>,
+    ) -> Result<Self, String> {
+        if store.is_empty() {
+            store.put(&genesis)?;
+        } else if store.get(0)?.hash() != genesis.hash() {
+            return Err("stored chain has a different genesis block".to_string());
+        }
+
+        let mut bc = Blockchain {
+            chain_id: genesis.header.chain_id,
+            store,
+            state,
+            undo_log: vec![],
+            block_index: HashMap::new(),
+            tx_index: HashMap::new(),
+            side_blocks: HashMap::new(),
+            finality_depth: FINALITY_DEPTH,
+            max_side_blocks: MAX_SIDE_BLOCKS,
+            fork_choice,
+            weights: HashMap::new(),
+            orphaned_transactions: vec![],
+            sig_cache: SigCache::new(DEFAULT_SIG_CACHE_SIZE),
+            validators: HashSet::new(),
         };
-        bc
+        let weight = bc.fork_choice.weight(&genesis);
+        bc.weights.insert(genesis.hash(), weight);
+        bc.index_block(&mut genesis);
+
+        for height in 1..bc.store.len() {
+            let mut block = bc.store.get(height)?;
+            let undo = bc
+                .state
+                .apply_transactions(&block.transactions)
+                .map_err(|e| format!("error replaying block {}: {}", height, e))?;
+            bc.undo_log.push(undo);
+
+            let weight =
+                bc.weight_of(&block.header.prev_block_hash) + bc.fork_choice.weight(&block);
+            bc.weights.insert(block.hash(), weight);
+            bc.index_block(&mut block);
+        }
+        Ok(bc)
     }
 
+    // blocks extending the head are executed straight away, blocks on other
+    // branches are kept and trigger a reorg once their branch outweighs the main chain
     pub fn add_block(&mut self, mut block: Block) -> Result<(), String> {
         self.verify(&mut block)?;
 
-        // execute transactions
+        let hash = block.hash();
+        let weight =
+            self.weight_of(&block.header.prev_block_hash) + self.fork_choice.weight(&block);
+        let head = self.head_hash()?;
+
+        if block.header.prev_block_hash == head {
+            self.connect_block(block)?;
+            self.weights.insert(hash, weight);
+            self.prune_side_blocks(None);
+            return Ok(());
+        }
+
+        match self.fork_height(&block.header.prev_block_hash) {
+            Some(height) if height >= self.final_height() => {}
+            _ => {
+                return Err(format!(
+                    "block {} forks below the final height {}",
+                    hash,
+                    self.final_height()
+                ))
+            }
+        }
+
+        self.weights.insert(hash, weight);
+        self.side_blocks.insert(hash, block);
+        if weight > self.weight_of(&head) {
+            self.reorganize(hash)?;
+            self.prune_side_blocks(None);
+            return Ok(());
+        }
+        if !self.prune_side_blocks(Some(hash)) {
+            return Err(format!("block {} is on the lightest side branch", hash));
+        }
+        Ok(())
+    }
+
+    // main chain blocks up to this height can no longer be replaced by a reorg
+    fn final_height(&self) -> u32 {
+        self.height().saturating_sub(self.finality_depth)
+    }
+
+    // the height of the main chain block a known block's branch forks from
+    fn fork_height(&self, hash: &Hash) -> Option<u32> {
+        let mut hash = *hash;
+        loop {
+            if let Some(height) = self.block_index.get(&hash) {
+                return Some(*height);
+            }
+            hash = self.side_blocks.get(&hash)?.header.prev_block_hash;
+        }
+    }
+
+    // drops side branches forking below the final height, then the lightest branch
+    // tips until at most max_side_blocks are left. Returns false if the block
+    // that was just added was dropped
+    fn prune_side_blocks(&mut self, added: Option<Hash>) -> bool {
+        let final_height = self.final_height();
+        let stale: Vec<Hash> = self
+            .side_blocks
+            .keys()
+            .filter(|hash| self.fork_height(hash).is_none_or(|h| h < final_height))
+            .copied()
+            .collect();
+        for hash in &stale {
+            self.forget_side_block(hash);
+        }
+
+        let mut kept = true;
+        while self.side_blocks.len() > self.max_side_blocks {
+            let parents: HashSet<Hash> = self
+                .side_blocks
+                .values()
+                .map(|block| block.header.prev_block_hash)
+                .collect();
+            // the newest block goes first among tips of equal weight
+            let lightest = self
+                .side_blocks
+                .keys()
+                .filter(|hash| !parents.contains(*hash))
+                .min_by_key(|hash| (self.weight_of(hash), Some(**hash) != added))
+                .copied()
+                .unwrap();
+            if Some(lightest) == added {
+                kept = false;
+            }
+            self.forget_side_block(&lightest);
+        }
+        kept
+    }
+
+    fn forget_side_block(&mut self, hash: &Hash) {
+        self.side_blocks.remove(hash);
+        self.weights.remove(hash);
+    }
+
+    // executes a block on top of the head and appends it to the main chain
+    fn connect_block(&mut self, mut block: Block) -> Result<(), String> {
+        let undo = self.state.apply_transactions(&block.transactions)?;
+        if let Err(err) = self.store.put(&block) {
+            self.state.revert(undo);
+            return Err(err);
+        }
+        self.undo_log.push(undo);
+        self.index_block(&mut block);
+        Ok(())
+    }
+
+    // removes the head from the main chain and reverts its state changes
+    fn disconnect_tip(&mut self) -> Result<Block, String> {
+        let height = self.height();
+        let mut block = self.store.get(height)?;
+        self.store.truncate(height)?;
+        if let Some(undo) = self.undo_log.pop() {
+            self.state.revert(undo);
+        }
+        self.unindex_block(&mut block);
+        Ok(block)
+    }
+
+    // switches the main chain to the side branch ending in tip. If a block on the
+    // branch fails to execute, the old main chain is restored and the failing
+    // block and its descendants on the branch are forgotten.
+    fn reorganize(&mut self, tip: Hash) -> Result<(), String> {
+        let mut branch = vec![];
+        let mut hash = tip;
+        while !self.block_index.contains_key(&hash) {
+            let block = self.side_blocks.remove(&hash).unwrap();
+            hash = block.header.prev_block_hash;
+            branch.push(block);
+        }
+        branch.reverse();
+        let fork_height = self.block_index[&hash];
+        println!(
+            "reorganizing chain at height {} => replacing {} blocks with {}",
+            fork_height,
+            self.height() - fork_height,
+            branch.len()
+        );
+
+        let mut disconnected = vec![];
+        while self.height() > fork_height {
+            disconnected.push(self.disconnect_tip()?);
+        }
+        disconnected.reverse();
+
+        for i in 0..branch.len() {
+            if let Err(err) = self.connect_block(branch[i].clone()) {
+                for _ in 0..i {
+                    self.disconnect_tip()?;
+                }
+                for block in disconnected {
+                    self.connect_block(block)?;
+                }
+                let invalid = branch.split_off(i);
+                for mut block in branch {
+                    self.side_blocks.insert(block.hash(), block);
+                }
+                for mut block in invalid {
+                    self.weights.remove(&block.hash());
+                }
+                return Err(format!("reorg failed, invalid side branch: {}", err));
+            }
+        }
 
-        // add transaction
-        self.blocks.push(block);
+        for mut block in disconnected {
+            for tx in block.transactions.iter_mut() {
+                if !self.tx_index.contains_key(&tx.hash()) {
+                    self.orphaned_transactions.push(tx.clone());
+                }
+            }
+            self.side_blocks.insert(block.hash(), block);
+        }
         Ok(())
     }
 
+    fn index_block(&mut self, block: &mut Block) {
+        let height = block.header.height;
+        self.block_index.insert(block.hash(), height);
+        for (i, tx) in block.transactions.iter_mut().enumerate() {
+            self.tx_index.insert(tx.hash(), (height, i));
+        }
+    }
+
+    fn unindex_block(&mut self, block: &mut Block) {
+        let height = block.header.height;
+        self.block_index.remove(&block.hash());
+        for (i, tx) in block.transactions.iter_mut().enumerate() {
+            if self.tx_index.get(&tx.hash()) == Some(&(height, i)) {
+                self.tx_index.remove(&tx.hash());
+            }
+        }
+    }
+
+    // returns the transactions dropped from the main chain by reorgs since the last call,
+    // so they can be put back into the mempool
+    pub fn take_orphaned_transactions(&mut self) -> Vec<Transaction> {
+        std::mem::take(&mut self.orphaned_transactions)
+    }
+
+    pub fn balance_of(&self, address: &Address) -> u64 {
+        self.state.balance_of(address)
+    }
+
+    pub fn state(&self) -> &State {
+        &self.state
+    }
+
     pub fn verify(&mut self, block: &mut Block) -> Result<(), String> {
-        if self.has_block(block.header.height) {
+        if block.header.chain_id != self.chain_id {
             return Err(format!(
-                "chain already contains block with height {} => hash {}",
-                block.header.height,
-                self.get_block(block.header.height).unwrap().hash(),
+                "block {} is for chain {} => expected chain {}",
+                block.hash(),
+                block.header.chain_id,
+                self.chain_id,
             ));
         }
 
-        if block.header.height != self.height() + 1 {
+        let hash = block.hash();
+        if !self.validators.contains(&block.header.validator) {
             return Err(format!(
-                "block {} with height {} is too high => current height {}",
-                block.hash(),
-                block.header.height,
-                self.height(),
+                "block {} names {} which is not a validator",
+                hash,
+                encode_address(&block.header.validator),
             ));
         }
 
-        let prev_header = self.get_header(block.header.height - 1).unwrap();
-        let prev_hash = prev_header.hash();
+        if self.is_known(&hash) {
+            return Err(format!(
+                "chain already contains block with height {} => hash {}",
+                block.header.height, hash,
+            ));
+        }
+
+        let mut prev_header = match self.get_known_header(&block.header.prev_block_hash) {
+            Some(header) => header,
+            None => {
+                return Err(format!(
+                "block {} with height {} is too high => parent {} is unknown, current height {}",
+                hash,
+                block.header.height,
+                block.header.prev_block_hash,
+                self.height(),
+            ))
+            }
+        };
 
-        if block.header.prev_block_hash != prev_hash {
+        if block.header.height != prev_header.height + 1 {
             return Err(format!(
-                "the hash of the previous block {} is invalid",
-                prev_hash
+                "block {} has height {} but its parent {} has height {}",
+                hash,
+                block.header.height,
+                prev_header.hash(),
+                prev_header.height,
             ));
         }
 
-        block.verify()
+        block.verify_with_cache(&self.sig_cache)
+    }
+
+    pub fn set_validators(&mut self, validators: impl IntoIterator<Item = Address>) {
+        self.validators = validators.into_iter().collect();
+    }
+
+    pub fn is_validator(&self, address: &Address) -> bool {
+        self.validators.contains(address)
     }
 
-    pub fn get_block(&mut self, height: u32) -> Result<&mut Block, String> {
-        // TODO: add mutex
+    // a handle to the cache of verified signatures. Transactions verified through
+    // it before they reach a block are not checked again
+    pub fn sig_cache(&self) -> SigCache {
+        self.sig_cache.clone()
+    }
+
+    // looks up the header of a block on the main chain or a side branch
+    pub fn get_known_header(&self, hash: &Hash) -> Option<Header> {
+        if let Some(height) = self.block_index.get(hash) {
+            return self.get_header(*height).ok();
+        }
+        self.side_blocks.get(hash).map(|block| block.header.clone())
+    }
+
+    fn weight_of(&self, hash: &Hash) -> u64 {
+        *self.weights.get(hash).unwrap_or(&0)
+    }
+
+    pub fn head_hash(&self) -> Result<Hash, String> {
+        Ok(self.get_header(self.height())?.hash())
+    }
+
+    pub fn get_block(&self, height: u32) -> Result<Block, String> {
         if height > self.height() {
             return Err(format!("height {} too height", height));
         }
-        Ok(&mut self.blocks[height as usize])
+        self.store.get(height)
+    }
+
+    pub fn get_block_by_hash(&self, hash: &Hash) -> Result<Block, String> {
+        match self.block_index.get(hash) {
+            Some(height) => self.get_block(*height),
+            None => Err(format!("no block with hash {}", hash)),
+        }
+    }
+
+    // returns the transaction with the height of the block including it and its position in that block
+    pub fn get_transaction(&self, hash: &Hash) -> Result<(Transaction, u32, usize), String> {
+        let (height, index) = match self.tx_index.get(hash) {
+            Some(location) => *location,
+            None => return Err(format!("no transaction with hash {}", hash)),
+        };
+        let mut block = self.get_block(height)?;
+        Ok((block.transactions.swap_remove(index), height, index))
+    }
+
+    // proves a main chain transaction is included in its block, returned with the block header
+    pub fn merkle_proof(&self, tx_hash: &Hash) -> Result<(MerkleProof, Header), String> {
+        let height = match self.tx_index.get(tx_hash) {
+            Some((height, _)) => *height,
+            None => return Err(format!("no transaction with hash {}", tx_hash)),
+        };
+        let mut block = self.get_block(height)?;
+        match block.merkle_proof(tx_hash) {
+            Some(proof) => Ok((proof, block.header)),
+            None => Err(format!("no transaction with hash {}", tx_hash)),
+        }
+    }
+
+    // the height of a block on the main chain
+    pub fn height_of(&self, hash: &Hash) -> Option<u32> {
+        self.block_index.get(hash).copied()
+    }
+
+    // main chain hashes from the head back to genesis, one per height for the
+    // last ten blocks and then doubling the step, so a peer on another branch
+    // can find where it meets ours
+    pub fn block_locator(&self) -> Result<Vec<Hash>, String> {
+        let mut locator = vec![];
+        let mut height = self.height();
+        let mut step = 1;
+        loop {
+            locator.push(self.get_header(height)?.hash());
+            if height == 0 {
+                return Ok(locator);
+            }
+            if locator.len() >= 10 {
+                step *= 2;
+            }
+            height = height.saturating_sub(step);
+        }
+    }
+
+    // up to count main chain headers following the first locator hash on the main chain
+    pub fn headers_after(&self, locator: &[Hash], count: u32) -> Result<Vec<Header>, String> {
+        let start = match locator.iter().find_map(|hash| self.height_of(hash)) {
+            Some(height) => height + 1,
+            None => return Ok(vec![]),
+        };
+        let end = self
+            .height()
+            .min(start.saturating_add(count).saturating_sub(1));
+        (start..=end)
+            .map(|height| self.get_header(height))
+            .collect()
+    }
+
+    pub fn has_block_hash(&self, hash: &Hash) -> bool {
+        self.block_index.contains_key(hash)
+    }
+
+    // true for blocks on the main chain and on side branches
+    pub fn is_known(&self, hash: &Hash) -> bool {
+        self.block_index.contains_key(hash) || self.side_blocks.contains_key(hash)
     }
 
-    pub fn get_header(&mut self, height: u32) -> Result<&mut Header, String> {
+    pub fn get_known_block(&self, hash: &Hash) -> Result<Block, String> {
+        match self.side_blocks.get(hash) {
+            Some(block) => Ok(block.clone()),
+            None => self.get_block_by_hash(hash),
+        }
+    }
+
+    pub fn get_header(&self, height: u32) -> Result<Header, String> {
         let block = self.get_block(height)?;
-        return Ok(&mut block.header);
+        Ok(block.header)
     }
 
     pub fn has_block(&self, height: u32) -> bool {
         height <= self.height()
     }
 
+    pub fn chain_id(&self) -> u32 {
+        self.chain_id
+    }
+
     pub fn height(&self) -> u32 {
-        self.blocks.len() as u32 - 1
+        self.store.len() - 1
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::core::hasher::verify_merkle_proof;
+    use crate::core::storage::FileBlockStore;
+    use crate::core::transaction::Transaction;
+    use crate::crypto::keypair::KeyPair;
+    use crate::types::hash::Hash;
 
+    // random_block transfers 5 from KeyPair::new(0) and is signed by it,
+    // so give that key some funds and make it the validator
     pub fn new_blockchain_with_genesis() -> Blockchain {
-        let block = random_block(0, "".to_string());
-        Blockchain::new(block)
+        let block = random_block(0, Hash::zero());
+        let mut state = State::new();
+        state.credit(KeyPair::new(0).address(), 10);
+        let mut bc = Blockchain::new_with_state(block, state);
+        bc.set_validators([KeyPair::new(0).address()]);
+        bc
     }
 
     pub fn prev_block_hash(bc: &mut Blockchain, height: u32) -> Hash {
@@ -104,6 +557,325 @@ mod tests {
         println!("{:?}", bc);
     }
 
+    #[test]
+    fn test_add_block_executes_transactions() {
+        let mut bc = new_blockchain_with_genesis();
+        let sender = KeyPair::new(0).address();
+
+        let block = random_block(1, prev_block_hash(&mut bc, 1));
+        assert!(bc.add_block(block).is_ok());
+        assert_eq!(bc.balance_of(&sender), 5);
+        assert_eq!(bc.balance_of(&[0; 20]), 5);
+
+        let block = random_block(2, prev_block_hash(&mut bc, 2));
+        assert!(bc.add_block(block).is_ok());
+        assert_eq!(bc.balance_of(&sender), 0);
+
+        // a third transfer of 5 overdraws the sender, so the block is rejected
+        let block = random_block(3, prev_block_hash(&mut bc, 3));
+        assert!(bc.add_block(block).is_err());
+        assert_eq!(bc.height(), 2);
+        assert_eq!(bc.balance_of(&[0; 20]), 10);
+    }
+
+    #[test]
+    fn test_open_replays_stored_blocks() {
+        let dir = std::env::temp_dir().join(format!("blockchain-open-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let sender = KeyPair::new(0).address();
+        let genesis_state = new_blockchain_with_genesis().state;
+
+        {
+            let store = Box::new(FileBlockStore::open(&dir).unwrap());
+            let mut bc =
+                Blockchain::open(random_block(0, Hash::zero()), genesis_state.clone(), store)
+                    .unwrap();
+            bc.set_validators([sender]);
+            let block = random_block(1, prev_block_hash(&mut bc, 1));
+            assert!(bc.add_block(block).is_ok());
+        }
+
+        let store = Box::new(FileBlockStore::open(&dir).unwrap());
+        let mut bc =
+            Blockchain::open(random_block(0, Hash::zero()), genesis_state.clone(), store).unwrap();
+        bc.set_validators([sender]);
+        assert_eq!(bc.height(), 1);
+        assert_eq!(bc.balance_of(&sender), 5);
+        let block_hash = prev_block_hash(&mut bc, 2);
+        assert_eq!(bc.get_block_by_hash(&block_hash).unwrap().header.height, 1);
+
+        let block = random_block(2, prev_block_hash(&mut bc, 2));
+        assert!(bc.add_block(block).is_ok());
+        assert_eq!(bc.balance_of(&sender), 0);
+
+        // a store written for a different genesis is refused
+        let store = Box::new(FileBlockStore::open(&dir).unwrap());
+        let mut other_genesis = random_block(0, Hash::zero());
+        other_genesis.header.timestamp = 1;
+        other_genesis.sign(&KeyPair::new(0));
+        assert!(Blockchain::open(other_genesis, genesis_state, store).is_err());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_lookup_by_hash() {
+        let mut bc = new_blockchain_with_genesis();
+        let block = random_block(1, prev_block_hash(&mut bc, 1));
+        assert!(bc.add_block(block).is_ok());
+
+        let mut block = random_block(2, prev_block_hash(&mut bc, 2));
+        let block_hash = block.hash();
+        let tx_hash = block.transactions[0].hash();
+        assert!(bc.get_block_by_hash(&block_hash).is_err());
+        assert!(bc.get_transaction(&tx_hash).is_err());
+
+        assert!(bc.add_block(block).is_ok());
+        assert!(bc.has_block_hash(&block_hash));
+        assert_eq!(bc.get_block_by_hash(&block_hash).unwrap().header.height, 2);
+
+        let (mut tx, height, index) = bc.get_transaction(&tx_hash).unwrap();
+        assert_eq!(tx.hash(), tx_hash);
+        assert_eq!((height, index), (2, 0));
+
+        let (proof, header) = bc.merkle_proof(&tx_hash).unwrap();
+        assert_eq!(header.height, 2);
+        assert!(verify_merkle_proof(&header.data_hash, &proof));
+
+        let genesis_hash = prev_block_hash(&mut bc, 1);
+        assert_eq!(
+            bc.get_block_by_hash(&genesis_hash).unwrap().header.height,
+            0
+        );
+    }
+
+    fn block_with_transfer(height: u32, prev_hash: Hash, to: Address, amount: u64) -> Block {
+        let mut tx = Transaction::new(to, amount, height as u64 - 1, 0);
+        tx.sign(&KeyPair::new(0));
+        let header = Header::new(0, 0, Hash::zero(), prev_hash, 1, height);
+        let mut block = Block::new(header, vec![tx]);
+        block.header.data_hash = calculate_data_hash(&mut block.transactions);
+        block.sign(&KeyPair::new(0));
+        block
+    }
+
+    fn empty_block(height: u32, prev_hash: Hash) -> Block {
+        let header = Header::new(0, 0, Hash::zero(), prev_hash, 0, height);
+        let mut block = Block::new(header, vec![]);
+        block.header.data_hash = calculate_data_hash(&mut block.transactions);
+        block.sign(&KeyPair::new(0));
+        block
+    }
+
+    #[test]
+    fn test_reorg_to_longer_branch() {
+        let mut bc = new_blockchain_with_genesis();
+        let sender = KeyPair::new(0).address();
+        let genesis_hash = prev_block_hash(&mut bc, 1);
+
+        let mut a1 = random_block(1, genesis_hash);
+        let a1_hash = a1.hash();
+        let a1_tx_hash = a1.transactions[0].hash();
+        assert!(bc.add_block(a1.clone()).is_ok());
+
+        // a competing block of the same weight is kept but does not replace the head
+        let mut b1 = block_with_transfer(1, genesis_hash, [1; 20], 3);
+        let b1_hash = b1.hash();
+        assert!(bc.add_block(b1).is_ok());
+        assert_eq!(bc.head_hash().unwrap(), a1_hash);
+        assert_eq!(bc.balance_of(&[0; 20]), 5);
+
+        let b2 = block_with_transfer(2, b1_hash, [1; 20], 3);
+        assert!(bc.add_block(b2).is_ok());
+        assert_eq!(bc.height(), 2);
+        assert_eq!(bc.get_block(1).unwrap().hash(), b1_hash);
+        assert_eq!(bc.balance_of(&sender), 4);
+        assert_eq!(bc.balance_of(&[0; 20]), 0);
+        assert_eq!(bc.balance_of(&[1; 20]), 6);
+        assert!(bc.get_transaction(&a1_tx_hash).is_err());
+
+        let mut orphaned = bc.take_orphaned_transactions();
+        assert_eq!(orphaned.len(), 1);
+        assert_eq!(orphaned[0].hash(), a1_tx_hash);
+        assert!(bc.take_orphaned_transactions().is_empty());
+
+        // the old block is still known and its branch can win again
+        assert!(bc.add_block(a1).is_err());
+        let mut a2 = empty_block(2, a1_hash);
+        let a2_hash = a2.hash();
+        assert!(bc.add_block(a2).is_ok());
+        assert_eq!(bc.get_block(1).unwrap().hash(), b1_hash);
+
+        assert!(bc.add_block(empty_block(3, a2_hash)).is_ok());
+        assert_eq!(bc.height(), 3);
+        assert_eq!(bc.get_block(1).unwrap().hash(), a1_hash);
+        assert_eq!(bc.balance_of(&sender), 5);
+        assert_eq!(bc.balance_of(&[1; 20]), 0);
+        assert_eq!(bc.take_orphaned_transactions().len(), 2);
+    }
+
+    #[test]
+    fn test_reorg_to_invalid_branch_keeps_main_chain() {
+        let mut bc = new_blockchain_with_genesis();
+        let genesis_hash = prev_block_hash(&mut bc, 1);
+
+        let mut a1 = random_block(1, genesis_hash);
+        let a1_hash = a1.hash();
+        assert!(bc.add_block(a1).is_ok());
+
+        let mut b1 = block_with_transfer(1, genesis_hash, [1; 20], 3);
+        let b1_hash = b1.hash();
+        assert!(bc.add_block(b1).is_ok());
+
+        // overdraws the sender, so the branch cannot become the main chain
+        let mut b2 = block_with_transfer(2, b1_hash, [1; 20], 100);
+        let b2_hash = b2.hash();
+        assert!(bc.add_block(b2).is_err());
+        assert_eq!(bc.height(), 1);
+        assert_eq!(bc.head_hash().unwrap(), a1_hash);
+        assert_eq!(bc.balance_of(&[0; 20]), 5);
+        assert_eq!(bc.balance_of(&[1; 20]), 0);
+        assert!(bc.take_orphaned_transactions().is_empty());
+        assert!(!bc.side_blocks.contains_key(&b2_hash));
+        assert!(bc.side_blocks.contains_key(&b1_hash));
+    }
+
+    #[test]
+    fn test_side_blocks_are_bounded() {
+        let mut bc = new_blockchain_with_genesis();
+        bc.finality_depth = 2;
+        bc.max_side_blocks = 2;
+        let genesis_hash = prev_block_hash(&mut bc, 1);
+        let fork = |height, prev_hash, timestamp| {
+            let mut block = empty_block(height, prev_hash);
+            block.header.timestamp = timestamp;
+            block.sign(&KeyPair::new(0));
+            block
+        };
+
+        let mut a1 = empty_block(1, genesis_hash);
+        let a1_hash = a1.hash();
+        assert!(bc.add_block(a1).is_ok());
+        let mut a2 = empty_block(2, a1_hash);
+        let a2_hash = a2.hash();
+        assert!(bc.add_block(a2).is_ok());
+
+        let mut b1 = fork(1, genesis_hash, 1);
+        let b1_hash = b1.hash();
+        assert!(bc.add_block(b1).is_ok());
+        let mut c1 = fork(1, genesis_hash, 2);
+        let c1_hash = c1.hash();
+        assert!(bc.add_block(c1).is_ok());
+
+        // the cap is reached, a new tip as light as the others is refused
+        let mut d1 = fork(1, genesis_hash, 3);
+        let d1_hash = d1.hash();
+        assert!(bc.add_block(d1).is_err());
+        assert!(!bc.is_known(&d1_hash));
+
+        // a heavier tip replaces the lightest one
+        let mut b2 = fork(2, b1_hash, 1);
+        let b2_hash = b2.hash();
+        assert!(bc.add_block(b2).is_ok());
+        assert_eq!(bc.side_blocks.len(), 2);
+        assert!(bc.is_known(&b1_hash) && bc.is_known(&b2_hash));
+        assert!(!bc.is_known(&c1_hash));
+        assert!(!bc.weights.contains_key(&c1_hash));
+
+        // once the fork point is final the branch is dropped
+        let mut a3 = empty_block(3, a2_hash);
+        let a3_hash = a3.hash();
+        assert!(bc.add_block(a3).is_ok());
+        assert!(bc.add_block(empty_block(4, a3_hash)).is_ok());
+        assert!(bc.side_blocks.is_empty());
+        assert!(!bc.weights.contains_key(&b2_hash));
+
+        assert!(bc.add_block(fork(2, a1_hash, 5)).is_err());
+        assert!(bc.add_block(fork(3, a2_hash, 5)).is_ok());
+        assert_eq!(bc.side_blocks.len(), 1);
+    }
+
+    struct MostTransactions;
+
+    impl ForkChoice for MostTransactions {
+        fn weight(&self, block: &Block) -> u64 {
+            block.transactions.len() as u64
+        }
+    }
+
+    #[test]
+    fn test_custom_fork_choice() {
+        let mut bc = Blockchain::open_with_fork_choice(
+            random_block(0, Hash::zero()),
+            new_blockchain_with_genesis().state,
+            Box::new(MemoryBlockStore::new()),
+            Box::new(MostTransactions),
+        )
+        .unwrap();
+        bc.set_validators([KeyPair::new(0).address()]);
+        let genesis_hash = prev_block_hash(&mut bc, 1);
+
+        let mut a1 = empty_block(1, genesis_hash);
+        let a1_hash = a1.hash();
+        assert!(bc.add_block(a1).is_ok());
+        assert!(bc.add_block(empty_block(2, a1_hash)).is_ok());
+
+        // a shorter branch with more transactions is heavier
+        let mut b1 = block_with_transfer(1, genesis_hash, [1; 20], 3);
+        let b1_hash = b1.hash();
+        assert!(bc.add_block(b1).is_ok());
+        assert_eq!(bc.height(), 1);
+        assert_eq!(bc.head_hash().unwrap(), b1_hash);
+    }
+
+    #[test]
+    fn test_verify_rejects_unknown_validator() {
+        let mut bc = new_blockchain_with_genesis();
+        let mut block = random_block(1, prev_block_hash(&mut bc, 1));
+        block.sign(&KeyPair::new(1));
+        assert!(bc.add_block(block.clone()).is_err());
+
+        bc.set_validators([KeyPair::new(1).address()]);
+        assert!(bc.add_block(block).is_ok());
+    }
+
+    #[test]
+    fn test_verify_rejects_other_chain() {
+        let mut bc = new_blockchain_with_genesis();
+        let mut block = random_block(1, prev_block_hash(&mut bc, 1));
+        block.header.chain_id = 1;
+
+        assert!(bc.add_block(block).is_err());
+        assert_eq!(bc.height(), 0);
+    }
+
+    #[test]
+    fn test_block_locator() {
+        let mut state = State::new();
+        state.credit(KeyPair::new(0).address(), 1000);
+        let mut bc = Blockchain::new_with_state(random_block(0, Hash::zero()), state);
+        bc.set_validators([KeyPair::new(0).address()]);
+        for height in 1..=30 {
+            let block = random_block(height, prev_block_hash(&mut bc, height));
+            bc.add_block(block).unwrap();
+        }
+
+        let locator = bc.block_locator().unwrap();
+        let heights: Vec<u32> = locator.iter().map(|h| bc.height_of(h).unwrap()).collect();
+        assert_eq!(
+            heights,
+            vec![30, 29, 28, 27, 26, 25, 24, 23, 22, 21, 19, 15, 7, 0]
+        );
+
+        // a peer that only shares the first 15 blocks gets headers from there on
+        let headers = bc
+            .headers_after(&[Hash::digest(b"unknown"), locator[11]], 5)
+            .unwrap();
+        let heights: Vec<u32> = headers.iter().map(|h| h.height).collect();
+        assert_eq!(heights, vec![16, 17, 18, 19, 20]);
+        assert_eq!(bc.headers_after(&locator[..1], 5).unwrap().len(), 0);
+        assert_eq!(bc.headers_after(&[], 5).unwrap().len(), 0);
+    }
+
     // fn test_verify_block {
     //     let block1 = random_block();
     //     let bc = new_blockchain(block);
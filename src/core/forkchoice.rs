@@ -0,0 +1,16 @@
+use super::block::Block;
+
+// A fork choice rule gives every block a weight. The chain follows the branch
+// with the highest total weight from genesis; on a tie the current head is kept.
+pub trait ForkChoice: Send + Sync {
+    fn weight(&self, block: &Block) -> u64;
+}
+
+// every block weighs the same, so the longest branch wins
+pub struct LongestChain;
+
+impl ForkChoice for LongestChain {
+    fn weight(&self, _block: &Block) -> u64 {
+        1
+    }
+}
@@ -0,0 +1,147 @@
+use crate::types::hash::Hash;
+use serde::{Deserialize, Serialize};
+
+// leaves and inner nodes are hashed with different prefixes, so an inner node
+// can never be passed off as a leaf
+const LEAF_PREFIX: u8 = 0x00;
+const NODE_PREFIX: u8 = 0x01;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ProofStep {
+    pub hash: Hash,
+    // whether the sibling is the left input of the parent node
+    pub left: bool,
+}
+
+// the path from a leaf to the merkle root
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct MerkleProof {
+    pub leaf: Hash,
+    pub steps: Vec<ProofStep>,
+}
+
+fn hash_leaf(leaf: &Hash) -> Hash {
+    let mut data = vec![LEAF_PREFIX];
+    data.extend(leaf.as_bytes());
+    Hash::digest(&data)
+}
+
+fn hash_node(left: &Hash, right: &Hash) -> Hash {
+    let mut data = vec![NODE_PREFIX];
+    data.extend(left.as_bytes());
+    data.extend(right.as_bytes());
+    Hash::digest(&data)
+}
+
+// builds every level of the tree, from the hashed leaves up to the root. A node
+// without a sibling is carried up to the next level unchanged.
+fn levels(leaves: &[Hash]) -> Vec<Vec<Hash>> {
+    let mut level: Vec<Hash> = leaves.iter().map(hash_leaf).collect();
+    let mut levels = vec![];
+    while level.len() > 1 {
+        let next = level
+            .chunks(2)
+            .map(|pair| match pair {
+                [left, right] => hash_node(left, right),
+                [single] => *single,
+                _ => unreachable!(),
+            })
+            .collect();
+        levels.push(level);
+        level = next;
+    }
+    levels.push(level);
+    levels
+}
+
+pub fn merkle_root(leaves: &[Hash]) -> Hash {
+    if leaves.is_empty() {
+        return Hash::digest(&[]);
+    }
+    levels(leaves).pop().unwrap().pop().unwrap()
+}
+
+pub fn merkle_proof(leaves: &[Hash], leaf: &Hash) -> Option<MerkleProof> {
+    let mut index = leaves.iter().position(|l| l == leaf)?;
+    let levels = levels(leaves);
+
+    let mut steps = vec![];
+    for level in &levels[..levels.len() - 1] {
+        let sibling = index ^ 1;
+        if sibling < level.len() {
+            steps.push(ProofStep {
+                hash: level[sibling],
+                left: sibling < index,
+            });
+        }
+        index /= 2;
+    }
+    Some(MerkleProof { leaf: *leaf, steps })
+}
+
+pub fn verify_merkle_proof(root: &Hash, proof: &MerkleProof) -> bool {
+    let mut hash = hash_leaf(&proof.leaf);
+    for step in &proof.steps {
+        hash = if step.left {
+            hash_node(&step.hash, &hash)
+        } else {
+            hash_node(&hash, &step.hash)
+        };
+    }
+    &hash == root
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn leaves(n: usize) -> Vec<Hash> {
+        (0..n).map(|i| Hash::digest(&i.to_le_bytes())).collect()
+    }
+
+    #[test]
+    fn test_merkle_root() {
+        assert_eq!(merkle_root(&[]), Hash::digest(&[]));
+
+        let l = leaves(3);
+        let root = merkle_root(&l);
+        assert_eq!(root, merkle_root(&l));
+        assert_ne!(root, merkle_root(&leaves(4)));
+
+        // the order of the leaves is committed to
+        let swapped = vec![l[1], l[0], l[2]];
+        assert_ne!(root, merkle_root(&swapped));
+    }
+
+    #[test]
+    fn test_merkle_proof() {
+        for n in 1..10 {
+            let l = leaves(n);
+            let root = merkle_root(&l);
+            for leaf in &l {
+                let proof = merkle_proof(&l, leaf).unwrap();
+                assert!(verify_merkle_proof(&root, &proof));
+            }
+        }
+
+        let l = leaves(5);
+        assert!(merkle_proof(&l, &Hash::digest(b"missing")).is_none());
+    }
+
+    #[test]
+    fn test_verify_merkle_proof_failure() {
+        let l = leaves(5);
+        let root = merkle_root(&l);
+        let proof = merkle_proof(&l, &l[2]).unwrap();
+
+        let mut wrong_leaf = proof.clone();
+        wrong_leaf.leaf = l[3];
+        assert!(!verify_merkle_proof(&root, &wrong_leaf));
+
+        let mut wrong_step = proof.clone();
+        wrong_step.steps[0].left = !wrong_step.steps[0].left;
+        assert!(!verify_merkle_proof(&root, &wrong_step));
+
+        assert!(!verify_merkle_proof(&merkle_root(&leaves(4)), &proof));
+    }
+}
@@ -1,4 +1,9 @@
+pub mod assembler;
 pub mod block;
 pub mod blockchain;
+pub mod forkchoice;
 pub mod hasher;
+pub mod state;
+pub mod storage;
 pub mod transaction;
+pub mod verifier;
@@ -0,0 +1,274 @@
+use super::transaction::Transaction;
+use crate::types::address::Address;
+use std::collections::HashMap;
+use std::fmt;
+
+#[derive(Debug, PartialEq)]
+pub enum StateError {
+    InvalidSender(String),
+    NonceTooLow { expected: u64, got: u64 },
+    NonceGap { expected: u64, got: u64 },
+    InsufficientBalance { balance: u64, amount: u64 },
+    BalanceOverflow,
+}
+
+impl fmt::Display for StateError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            StateError::InvalidSender(err) => write!(f, "invalid sender: {}", err),
+            StateError::NonceTooLow { expected, got } => {
+                write!(f, "nonce too low => expected {}, got {}", expected, got)
+            }
+            StateError::NonceGap { expected, got } => {
+                write!(f, "nonce gap => expected {}, got {}", expected, got)
+            }
+            StateError::InsufficientBalance { balance, amount } => write!(
+                f,
+                "insufficient balance => balance {}, amount {}",
+                balance, amount
+            ),
+            StateError::BalanceOverflow => write!(f, "recipient balance overflows"),
+        }
+    }
+}
+
+// the values a block overwrote, so that applying it can be reverted during a reorg
+#[derive(Debug, Default, Clone)]
+pub struct StateUndo {
+    balances: Vec<(Address, Option<u64>)>,
+    nonces: Vec<(Address, Option<u64>)>,
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct State {
+    balances: HashMap<Address, u64>,
+    // the nonce the next transaction from each account must carry
+    nonces: HashMap<Address, u64>,
+}
+
+impl State {
+    pub fn new() -> Self {
+        State {
+            balances: HashMap::new(),
+            nonces: HashMap::new(),
+        }
+    }
+
+    pub fn balance_of(&self, address: &Address) -> u64 {
+        *self.balances.get(address).unwrap_or(&0)
+    }
+
+    pub fn nonce_of(&self, address: &Address) -> u64 {
+        *self.nonces.get(address).unwrap_or(&0)
+    }
+
+    // used to allocate funds at genesis, outside of any transaction
+    pub fn credit(&mut self, address: Address, amount: u64) {
+        let balance = self.balances.entry(address).or_insert(0);
+        *balance += amount;
+    }
+
+    // applies every transaction or none of them: changes are staged and only
+    // written back to the balances once the whole list has executed
+    pub fn apply_transactions(
+        &mut self,
+        transactions: &[Transaction],
+    ) -> Result<StateUndo, String> {
+        let mut balances: HashMap<Address, u64> = HashMap::new();
+        let mut nonces: HashMap<Address, u64> = HashMap::new();
+
+        for (i, tx) in transactions.iter().enumerate() {
+            let with_index = |err: StateError| format!("transaction {}: {}", i, err);
+
+            let sender = sender_address(tx).map_err(with_index)?;
+            let to = tx.data.to;
+            let amount = tx.data.amount;
+            // the fee leaves the sender's balance without reaching anyone
+            let cost = tx.cost();
+
+            let expected = *nonces.get(&sender).unwrap_or(&self.nonce_of(&sender));
+            check_nonce(expected, tx.data.nonce).map_err(with_index)?;
+            nonces.insert(sender, expected + 1);
+
+            let sender_balance = *balances.get(&sender).unwrap_or(&self.balance_of(&sender));
+            if sender_balance < cost {
+                return Err(with_index(StateError::InsufficientBalance {
+                    balance: sender_balance,
+                    amount: cost,
+                }));
+            }
+            balances.insert(sender, sender_balance - cost);
+
+            let to_balance = *balances.get(&to).unwrap_or(&self.balance_of(&to));
+            let to_balance = match to_balance.checked_add(amount) {
+                Some(v) => v,
+                None => return Err(with_index(StateError::BalanceOverflow)),
+            };
+            balances.insert(to, to_balance);
+        }
+
+        let undo = StateUndo {
+            balances: balances
+                .keys()
+                .map(|address| (*address, self.balances.get(address).copied()))
+                .collect(),
+            nonces: nonces
+                .keys()
+                .map(|address| (*address, self.nonces.get(address).copied()))
+                .collect(),
+        };
+        self.balances.extend(balances);
+        self.nonces.extend(nonces);
+        Ok(undo)
+    }
+
+    pub fn revert(&mut self, undo: StateUndo) {
+        for (address, balance) in undo.balances {
+            match balance {
+                Some(balance) => self.balances.insert(address, balance),
+                None => self.balances.remove(&address),
+            };
+        }
+        for (address, nonce) in undo.nonces {
+            match nonce {
+                Some(nonce) => self.nonces.insert(address, nonce),
+                None => self.nonces.remove(&address),
+            };
+        }
+    }
+}
+
+// nonces must increase by exactly one per transaction from the same sender
+pub fn check_nonce(expected: u64, got: u64) -> Result<(), StateError> {
+    if got < expected {
+        return Err(StateError::NonceTooLow { expected, got });
+    }
+    if got > expected {
+        return Err(StateError::NonceGap { expected, got });
+    }
+    Ok(())
+}
+
+pub fn sender_address(tx: &Transaction) -> Result<Address, StateError> {
+    tx.sender().map_err(StateError::InvalidSender)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::keypair::KeyPair;
+
+    fn signed_transaction(key_pair: &KeyPair, to: Address, amount: u64, nonce: u64) -> Transaction {
+        let mut tx = Transaction::new(to, amount, nonce, 0);
+        tx.sign(key_pair);
+        tx
+    }
+
+    #[test]
+    fn test_apply_transactions() {
+        let key_pair = KeyPair::new(0);
+        let sender = key_pair.address();
+        let mut state = State::new();
+        state.credit(sender, 10);
+
+        let txs = vec![
+            signed_transaction(&key_pair, [1; 20], 4, 0),
+            signed_transaction(&key_pair, [2; 20], 6, 1),
+        ];
+        assert!(state.apply_transactions(&txs).is_ok());
+        assert_eq!(state.balance_of(&sender), 0);
+        assert_eq!(state.balance_of(&[1; 20]), 4);
+        assert_eq!(state.balance_of(&[2; 20]), 6);
+        assert_eq!(state.nonce_of(&sender), 2);
+    }
+
+    #[test]
+    fn test_apply_transactions_is_atomic() {
+        let key_pair = KeyPair::new(0);
+        let sender = key_pair.address();
+        let mut state = State::new();
+        state.credit(sender, 10);
+
+        // the second transaction overdraws, so the first must not be applied either
+        let txs = vec![
+            signed_transaction(&key_pair, [1; 20], 4, 0),
+            signed_transaction(&key_pair, [2; 20], 7, 1),
+        ];
+        assert!(state.apply_transactions(&txs).is_err());
+        assert_eq!(state.balance_of(&sender), 10);
+        assert_eq!(state.balance_of(&[1; 20]), 0);
+        assert_eq!(state.balance_of(&[2; 20]), 0);
+        assert_eq!(state.nonce_of(&sender), 0);
+    }
+
+    #[test]
+    fn test_apply_transactions_rejects_replay() {
+        let key_pair = KeyPair::new(0);
+        let sender = key_pair.address();
+        let mut state = State::new();
+        state.credit(sender, 10);
+
+        let tx = signed_transaction(&key_pair, [1; 20], 1, 0);
+        assert!(state.apply_transactions(std::slice::from_ref(&tx)).is_ok());
+        assert!(state.apply_transactions(&[tx]).is_err());
+
+        let tx = signed_transaction(&key_pair, [1; 20], 1, 2);
+        assert!(state.apply_transactions(&[tx]).is_err());
+        assert_eq!(state.balance_of(&sender), 9);
+    }
+
+    #[test]
+    fn test_fee_is_charged() {
+        let key_pair = KeyPair::new(0);
+        let sender = key_pair.address();
+        let mut state = State::new();
+        state.credit(sender, 10);
+
+        let mut tx = Transaction::new([1; 20], 4, 0, 0).with_fee(6);
+        tx.sign(&key_pair);
+        assert!(state.apply_transactions(&[tx]).is_ok());
+        assert_eq!(state.balance_of(&sender), 0);
+        assert_eq!(state.balance_of(&[1; 20]), 4);
+
+        let mut tx = Transaction::new([1; 20], 0, 1, 0).with_fee(1);
+        tx.sign(&key_pair);
+        assert!(state.apply_transactions(&[tx]).is_err());
+    }
+
+    #[test]
+    fn test_revert() {
+        let key_pair = KeyPair::new(0);
+        let sender = key_pair.address();
+        let mut state = State::new();
+        state.credit(sender, 10);
+
+        let txs = vec![signed_transaction(&key_pair, [1; 20], 4, 0)];
+        let undo = state.apply_transactions(&txs).unwrap();
+        assert_eq!(state.balance_of(&[1; 20]), 4);
+
+        state.revert(undo);
+        assert_eq!(state.balance_of(&sender), 10);
+        assert_eq!(state.balance_of(&[1; 20]), 0);
+        assert_eq!(state.nonce_of(&sender), 0);
+        assert!(state.apply_transactions(&txs).is_ok());
+    }
+
+    #[test]
+    fn test_check_nonce() {
+        assert_eq!(check_nonce(1, 1), Ok(()));
+        assert_eq!(
+            check_nonce(1, 0),
+            Err(StateError::NonceTooLow {
+                expected: 1,
+                got: 0
+            })
+        );
+        assert_eq!(
+            check_nonce(1, 3),
+            Err(StateError::NonceGap {
+                expected: 1,
+                got: 3
+            })
+        );
+    }
+}
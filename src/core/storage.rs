@@ -0,0 +1,454 @@
+use super::block::Block;
+use std::fs::{self, File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+// segments are rolled over once they grow past this size
+const DEFAULT_SEGMENT_SIZE: u64 = 64 * 1024 * 1024;
+
+// every record in a segment is prefixed by the length and crc32 of the encoded block
+const RECORD_HEADER_SIZE: u64 = 8;
+
+// every index entry is segment number (u32), offset (u64) and record length (u32)
+const INDEX_ENTRY_SIZE: u64 = 16;
+
+pub trait BlockStore: Send + Sync {
+    // blocks are appended in height order, so the block at height h is the (h + 1)th put
+    fn put(&mut self, block: &Block) -> Result<(), String>;
+    fn get(&self, height: u32) -> Result<Block, String>;
+    fn len(&self) -> u32;
+    // removes every block from height len onwards, used to roll back the tip during a reorg
+    fn truncate(&mut self, len: u32) -> Result<(), String>;
+
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+#[derive(Default)]
+pub struct MemoryBlockStore {
+    blocks: Vec<Block>,
+}
+
+impl MemoryBlockStore {
+    pub fn new() -> Self {
+        MemoryBlockStore { blocks: vec![] }
+    }
+}
+
+impl BlockStore for MemoryBlockStore {
+    fn put(&mut self, block: &Block) -> Result<(), String> {
+        self.blocks.push(block.clone());
+        Ok(())
+    }
+
+    fn get(&self, height: u32) -> Result<Block, String> {
+        match self.blocks.get(height as usize) {
+            Some(block) => Ok(block.clone()),
+            None => Err(format!("no block stored at height {}", height)),
+        }
+    }
+
+    fn len(&self) -> u32 {
+        self.blocks.len() as u32
+    }
+
+    fn truncate(&mut self, len: u32) -> Result<(), String> {
+        self.blocks.truncate(len as usize);
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct IndexEntry {
+    segment: u32,
+    offset: u64,
+    len: u32,
+}
+
+impl IndexEntry {
+    fn to_bytes(self) -> [u8; INDEX_ENTRY_SIZE as usize] {
+        let mut bytes = [0u8; INDEX_ENTRY_SIZE as usize];
+        bytes[0..4].copy_from_slice(&self.segment.to_le_bytes());
+        bytes[4..12].copy_from_slice(&self.offset.to_le_bytes());
+        bytes[12..16].copy_from_slice(&self.len.to_le_bytes());
+        bytes
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Self {
+        IndexEntry {
+            segment: u32::from_le_bytes(bytes[0..4].try_into().unwrap()),
+            offset: u64::from_le_bytes(bytes[4..12].try_into().unwrap()),
+            len: u32::from_le_bytes(bytes[12..16].try_into().unwrap()),
+        }
+    }
+}
+
+// what opening a store cut off after an unclean shutdown
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct Recovery {
+    // indexed blocks at the tip whose record was missing or corrupt
+    pub dropped_blocks: u32,
+    // segment bytes past the last readable record
+    pub dropped_bytes: u64,
+}
+
+// FileBlockStore keeps blocks in append-only segment files inside a directory,
+// together with an index file mapping each height to its record.
+//
+// A block is written to its segment and synced before its index entry is
+// appended and synced, so the index never points at data that is not on disk.
+// On open, only the tail is checked: index entries at the tip whose record is
+// missing or fails its checksum are dropped, and any bytes past the last
+// indexed record are truncated away.
+pub struct FileBlockStore {
+    dir: PathBuf,
+    segment_size: u64,
+    index: Vec<IndexEntry>,
+    index_file: File,
+    recovery: Recovery,
+}
+
+impl FileBlockStore {
+    pub fn open<P: AsRef<Path>>(dir: P) -> Result<Self, String> {
+        Self::open_with_segment_size(dir, DEFAULT_SEGMENT_SIZE)
+    }
+
+    pub fn open_with_segment_size<P: AsRef<Path>>(
+        dir: P,
+        segment_size: u64,
+    ) -> Result<Self, String> {
+        let dir = dir.as_ref().to_path_buf();
+        fs::create_dir_all(&dir).map_err(|e| format!("error creating block store: {}", e))?;
+
+        let mut index_file = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create(true)
+            .open(dir.join("index.dat"))
+            .map_err(|e| format!("error opening block index: {}", e))?;
+
+        let mut bytes = vec![];
+        index_file
+            .read_to_end(&mut bytes)
+            .map_err(|e| format!("error reading block index: {}", e))?;
+        let index = bytes
+            .chunks_exact(INDEX_ENTRY_SIZE as usize)
+            .map(IndexEntry::from_bytes)
+            .collect();
+
+        let mut store = FileBlockStore {
+            dir,
+            segment_size,
+            index,
+            index_file,
+            recovery: Recovery::default(),
+        };
+        store.recovery = store.recover()?;
+        Ok(store)
+    }
+
+    // what was dropped when the store was opened
+    pub fn recovery(&self) -> Recovery {
+        self.recovery
+    }
+
+    fn segment_path(&self, segment: u32) -> PathBuf {
+        self.dir.join(format!("segment-{:05}.dat", segment))
+    }
+
+    fn read_record(&self, entry: &IndexEntry) -> Result<Vec<u8>, String> {
+        let mut file = File::open(self.segment_path(entry.segment))
+            .map_err(|e| format!("error opening segment {}: {}", entry.segment, e))?;
+        file.seek(SeekFrom::Start(entry.offset))
+            .map_err(|e| format!("error reading segment {}: {}", entry.segment, e))?;
+
+        let mut record = vec![0u8; RECORD_HEADER_SIZE as usize + entry.len as usize];
+        file.read_exact(&mut record)
+            .map_err(|e| format!("error reading segment {}: {}", entry.segment, e))?;
+
+        let len = u32::from_le_bytes(record[0..4].try_into().unwrap());
+        let checksum = u32::from_le_bytes(record[4..8].try_into().unwrap());
+        let data = record.split_off(RECORD_HEADER_SIZE as usize);
+        if len != entry.len || checksum != crc32fast::hash(&data) {
+            return Err(format!(
+                "corrupt record at segment {} offset {}",
+                entry.segment, entry.offset
+            ));
+        }
+        Ok(data)
+    }
+
+    // drops everything after the last readable block, which is where a crash
+    // in the middle of put leaves a partial write. Records are synced before
+    // their index entry, so only the tip needs checking
+    fn recover(&mut self) -> Result<Recovery, String> {
+        let mut valid = self.index.len();
+        while valid > 0 && self.read_record(&self.index[valid - 1]).is_err() {
+            valid -= 1;
+        }
+        let dropped_blocks = (self.index.len() - valid) as u32;
+        let dropped_bytes = self.truncate_to(valid as u32)?;
+        Ok(Recovery {
+            dropped_blocks,
+            dropped_bytes,
+        })
+    }
+
+    // shrinks the index to len entries and removes all segment data after the
+    // last remaining record, including whole segments. Returns the number of
+    // segment bytes removed
+    fn truncate_to(&mut self, len: u32) -> Result<u64, String> {
+        self.index.truncate(len as usize);
+        self.index_file
+            .set_len(self.index.len() as u64 * INDEX_ENTRY_SIZE)
+            .and_then(|_| self.index_file.sync_data())
+            .map_err(|e| format!("error truncating block index: {}", e))?;
+
+        let (last_segment, end) = match self.index.last() {
+            Some(entry) => (
+                entry.segment,
+                entry.offset + RECORD_HEADER_SIZE + entry.len as u64,
+            ),
+            None => (0, 0),
+        };
+        let mut removed = 0;
+        let mut segment = last_segment;
+        loop {
+            let path = self.segment_path(segment);
+            let size = match fs::metadata(&path) {
+                Ok(metadata) => metadata.len(),
+                Err(_) => break,
+            };
+            if segment == last_segment {
+                removed += size.saturating_sub(end);
+                OpenOptions::new()
+                    .write(true)
+                    .open(&path)
+                    .and_then(|f| f.set_len(end))
+                    .map_err(|e| format!("error truncating segment {}: {}", segment, e))?;
+            } else {
+                removed += size;
+                fs::remove_file(&path)
+                    .map_err(|e| format!("error removing segment {}: {}", segment, e))?;
+            }
+            segment += 1;
+        }
+        Ok(removed)
+    }
+}
+
+impl BlockStore for FileBlockStore {
+    fn put(&mut self, block: &Block) -> Result<(), String> {
+        let data = block.encode().into_bytes();
+
+        let (mut segment, mut offset) = match self.index.last() {
+            Some(entry) => (
+                entry.segment,
+                entry.offset + RECORD_HEADER_SIZE + entry.len as u64,
+            ),
+            None => (0, 0),
+        };
+        if offset > 0 && offset + RECORD_HEADER_SIZE + data.len() as u64 > self.segment_size {
+            segment += 1;
+            offset = 0;
+        }
+
+        let mut file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(self.segment_path(segment))
+            .map_err(|e| format!("error opening segment {}: {}", segment, e))?;
+        file.seek(SeekFrom::Start(offset))
+            .and_then(|_| file.write_all(&(data.len() as u32).to_le_bytes()))
+            .and_then(|_| file.write_all(&crc32fast::hash(&data).to_le_bytes()))
+            .and_then(|_| file.write_all(&data))
+            .and_then(|_| file.sync_data())
+            .map_err(|e| format!("error writing segment {}: {}", segment, e))?;
+
+        let entry = IndexEntry {
+            segment,
+            offset,
+            len: data.len() as u32,
+        };
+        self.index_file
+            .write_all(&entry.to_bytes())
+            .and_then(|_| self.index_file.sync_data())
+            .map_err(|e| format!("error writing block index: {}", e))?;
+        self.index.push(entry);
+        Ok(())
+    }
+
+    fn get(&self, height: u32) -> Result<Block, String> {
+        let entry = match self.index.get(height as usize) {
+            Some(entry) => entry,
+            None => return Err(format!("no block stored at height {}", height)),
+        };
+        let data = self.read_record(entry)?;
+        serde_json::from_slice(&data).map_err(|_| format!("error decoding block {}", height))
+    }
+
+    fn len(&self) -> u32 {
+        self.index.len() as u32
+    }
+
+    fn truncate(&mut self, len: u32) -> Result<(), String> {
+        self.truncate_to(len).map(|_| ())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::block::random_block;
+    use crate::types::hash::Hash;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!(
+            "blockchain-storage-{}-{}",
+            name,
+            std::process::id()
+        ));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    fn put_blocks(store: &mut dyn BlockStore, count: u32) {
+        for height in 0..count {
+            store.put(&random_block(height, Hash::zero())).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_memory_store() {
+        let mut store = MemoryBlockStore::new();
+        put_blocks(&mut store, 3);
+
+        assert_eq!(store.len(), 3);
+        assert_eq!(store.get(2).unwrap().header.height, 2);
+        assert!(store.get(3).is_err());
+    }
+
+    #[test]
+    fn test_file_store_reopen() {
+        let dir = temp_dir("reopen");
+        {
+            let mut store = FileBlockStore::open_with_segment_size(&dir, 1024).unwrap();
+            put_blocks(&mut store, 5);
+        }
+
+        let store = FileBlockStore::open_with_segment_size(&dir, 1024).unwrap();
+        assert_eq!(store.len(), 5);
+        for height in 0..5 {
+            let mut block = store.get(height).unwrap();
+            assert_eq!(block.header.height, height);
+            assert_eq!(block.hash(), random_block(height, Hash::zero()).hash());
+        }
+        // small segments force the blocks to be spread over several files
+        assert!(dir.join("segment-00001.dat").exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_file_store_recovers_truncated_tail() {
+        let dir = temp_dir("truncated");
+        {
+            let mut store = FileBlockStore::open(&dir).unwrap();
+            put_blocks(&mut store, 3);
+        }
+
+        // simulate a crash halfway through writing the last block
+        let segment = dir.join("segment-00000.dat");
+        let size = fs::metadata(&segment).unwrap().len();
+        OpenOptions::new()
+            .write(true)
+            .open(&segment)
+            .unwrap()
+            .set_len(size - 10)
+            .unwrap();
+
+        let mut store = FileBlockStore::open(&dir).unwrap();
+        assert_eq!(store.len(), 2);
+        assert!(store.get(2).is_err());
+        assert_eq!(store.recovery().dropped_blocks, 1);
+        assert!(store.recovery().dropped_bytes > 0);
+
+        // the store keeps working after recovery
+        store.put(&random_block(2, Hash::zero())).unwrap();
+        let store = FileBlockStore::open(&dir).unwrap();
+        assert_eq!(store.len(), 3);
+        assert_eq!(store.get(2).unwrap().header.height, 2);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_file_store_open_checks_only_the_tip() {
+        let dir = temp_dir("tip");
+        {
+            let mut store = FileBlockStore::open(&dir).unwrap();
+            put_blocks(&mut store, 3);
+        }
+
+        // damage the first record, which open does not read
+        let segment = dir.join("segment-00000.dat");
+        let mut bytes = fs::read(&segment).unwrap();
+        bytes[RECORD_HEADER_SIZE as usize] ^= 0xff;
+        fs::write(&segment, bytes).unwrap();
+
+        let store = FileBlockStore::open(&dir).unwrap();
+        assert_eq!(store.len(), 3);
+        assert_eq!(store.recovery(), Recovery::default());
+        assert!(store.get(0).is_err());
+        assert_eq!(store.get(2).unwrap().header.height, 2);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_file_store_truncate() {
+        let dir = temp_dir("truncate");
+        let mut store = FileBlockStore::open_with_segment_size(&dir, 1024).unwrap();
+        put_blocks(&mut store, 5);
+
+        store.truncate(2).unwrap();
+        assert_eq!(store.len(), 2);
+        assert!(store.get(2).is_err());
+
+        store.put(&random_block(2, Hash::zero())).unwrap();
+        let store = FileBlockStore::open_with_segment_size(&dir, 1024).unwrap();
+        assert_eq!(store.len(), 3);
+        assert_eq!(store.get(2).unwrap().header.height, 2);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_file_store_drops_unindexed_data() {
+        let dir = temp_dir("unindexed");
+        {
+            let mut store = FileBlockStore::open(&dir).unwrap();
+            put_blocks(&mut store, 2);
+        }
+
+        // simulate a crash after the segment write but before the index write
+        let index = dir.join("index.dat");
+        let size = fs::metadata(&index).unwrap().len();
+        OpenOptions::new()
+            .write(true)
+            .open(&index)
+            .unwrap()
+            .set_len(size - 3)
+            .unwrap();
+
+        let mut store = FileBlockStore::open(&dir).unwrap();
+        assert_eq!(store.len(), 1);
+        let recovery = store.recovery();
+        assert_eq!(recovery.dropped_blocks, 0);
+        assert_eq!(
+            recovery.dropped_bytes,
+            RECORD_HEADER_SIZE + random_block(1, Hash::zero()).encode().len() as u64
+        );
+        store.put(&random_block(1, Hash::zero())).unwrap();
+        assert_eq!(store.get(1).unwrap().header.height, 1);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,76 +1,269 @@
 use crate::crypto::keypair::{new_pk_from_string, new_sig_from_string};
+use crate::crypto::multisig::{Multisig, MultisigAccount};
+use crate::crypto::scheme::{Scheme, Signer};
+use crate::types::address::{address_from_public_key, Address};
 use crate::types::hash::Hash;
-use crate::{crypto::keypair::KeyPair, types::address::Address};
-use secp256k1::{
-    rand::{rngs, SeedableRng},
-    Message, PublicKey, SecretKey, Signature,
-};
 use serde::{Deserialize, Serialize};
-use sha256::{digest, try_digest};
-use std::str::FromStr;
+
+// the version of the signed encoding. Version 0 transactions, from before
+// versioning, are signed with a lossy digest of their JSON data and carry the
+// public key. They are still accepted but no longer created.
+pub const TX_VERSION: u8 = 1;
+const LEGACY_TX_VERSION: u8 = 0;
+// stands in for the scheme tag of transactions sent from a multisig account
+const MULTISIG_SENDER_TAG: u8 = 0xff;
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct Transaction {
+    // missing from transactions encoded before versioning, which decode as version 0
+    #[serde(default)]
+    pub version: u8,
+    // transactions without one are secp256k1
+    #[serde(default)]
+    pub scheme: Scheme,
+    // only needed for signatures the key can't be recovered from
+    #[serde(default, skip_serializing_if = "Option::is_none")]
     pub public_key: Option<String>,
     pub signature: Option<String>,
+    // set instead of the signature for transactions sent from a multisig account
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub multisig: Option<Multisig>,
+    // the address of the signing key or multisig account, set when signing
+    #[serde(default)]
+    pub from: Address,
 
     pub data: Data,
 
-    #[serde(skip_serializing)]
+    #[serde(skip)]
     hash: Option<Hash>,
 
-    #[serde(skip_serializing)]
+    // when the mempool received it, in milliseconds since the epoch
+    #[serde(skip)]
     first_seen: Option<i64>,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct Data {
-    to: Address,
+    pub to: Address,
+    pub amount: u64,
+    // paid by the sender on top of the amount, and burnt. Block producers pick
+    // the transactions paying the most per byte first
+    #[serde(default)]
+    pub fee: u64,
+    // the number of transactions the sender has sent before this one
+    pub nonce: u64,
+    // identifies the network the signature is valid on
+    pub chain_id: u32,
+}
+
+// the data of a version 0 transaction as it was signed, which had no fee
+#[derive(Serialize)]
+struct LegacyData<'a> {
+    to: &'a Address,
     amount: u64,
+    nonce: u64,
+    chain_id: u32,
+}
+
+impl Data {
+    // fixed-width little-endian fields in declaration order
+    pub fn encode(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(48);
+        bytes.extend_from_slice(&self.to);
+        bytes.extend_from_slice(&self.amount.to_le_bytes());
+        bytes.extend_from_slice(&self.fee.to_le_bytes());
+        bytes.extend_from_slice(&self.nonce.to_le_bytes());
+        bytes.extend_from_slice(&self.chain_id.to_le_bytes());
+        bytes
+    }
 }
 
 impl Transaction {
-    pub fn new(to: Address, amount: u64) -> Self {
+    pub fn new(to: Address, amount: u64, nonce: u64, chain_id: u32) -> Self {
         Self {
-            data: Data { to, amount },
+            version: TX_VERSION,
+            scheme: Scheme::default(),
+            data: Data {
+                to,
+                amount,
+                fee: 0,
+                nonce,
+                chain_id,
+            },
             public_key: None,
             signature: None,
+            multisig: None,
+            from: [0; 20],
             hash: None,
             first_seen: None,
         }
     }
 
+    // sets the fee, before the transaction is signed
+    pub fn with_fee(mut self, fee: u64) -> Self {
+        self.data.fee = fee;
+        self.hash = None;
+        self
+    }
+
+    // what the sender's balance has to cover: the amount and the fee
+    pub fn cost(&self) -> u64 {
+        self.data.amount.saturating_add(self.data.fee)
+    }
+
+    pub fn first_seen(&self) -> Option<i64> {
+        self.first_seen
+    }
+
+    pub fn set_first_seen(&mut self, first_seen: i64) {
+        self.first_seen = Some(first_seen);
+    }
+
     pub fn encode(&self) -> String {
         serde_json::to_string(&self).unwrap()
     }
 
-    pub fn encode_for_hash(&self) -> String {
-        serde_json::to_string(&self.data).unwrap()
+    // the bytes that are hashed and signed: the version, the sender's scheme tag
+    // and address, then the encoded data. Committing to the sender keeps the same
+    // data sent from two accounts apart. Version 0 transactions used the JSON data
+    pub fn encode_for_hash(&self) -> Vec<u8> {
+        if self.version == LEGACY_TX_VERSION {
+            return serde_json::to_vec(&LegacyData {
+                to: &self.data.to,
+                amount: self.data.amount,
+                nonce: self.data.nonce,
+                chain_id: self.data.chain_id,
+            })
+            .unwrap();
+        }
+        let tag = match self.multisig {
+            Some(_) => MULTISIG_SENDER_TAG,
+            None => self.scheme.tag(),
+        };
+        let mut bytes = vec![self.version, tag];
+        bytes.extend_from_slice(&self.from);
+        bytes.extend(self.data.encode());
+        bytes
     }
 
     pub fn hash(&mut self) -> Hash {
-        if self.hash == None {
-            self.hash = Some(digest(self.encode_for_hash()));
+        if self.hash.is_none() {
+            self.hash = Some(Hash::digest(&self.encode_for_hash()));
+        }
+        self.hash.unwrap()
+    }
+
+    pub fn sign(&mut self, signer: &dyn Signer) {
+        self.version = TX_VERSION;
+        self.scheme = signer.scheme();
+        self.from = signer.address();
+        self.multisig = None;
+        self.hash = None;
+        let signed = signer.sign(&self.encode_for_hash());
+        self.signature = Some(signed.signature);
+        self.public_key = signed.public_key;
+    }
+
+    // adds a signature from one of the account's keys. The data must not change
+    // once signing has started, as that would invalidate the earlier signatures
+    pub fn sign_multisig(
+        &mut self,
+        account: &MultisigAccount,
+        signer: &dyn Signer,
+    ) -> Result<(), String> {
+        self.version = TX_VERSION;
+        self.from = account.address();
+        self.signature = None;
+        self.public_key = None;
+        self.hash = None;
+        if self.multisig.as_ref().is_none_or(|m| m.account != *account) {
+            self.multisig = Some(Multisig::new(account.clone()));
+        }
+        let data = self.encode_for_hash();
+        self.multisig.as_mut().unwrap().sign(signer, &data)
+    }
+
+    // the address of the key that signed the transaction, or of the multisig account
+    pub fn sender(&self) -> Result<Address, String> {
+        if self.version == LEGACY_TX_VERSION {
+            return self
+                .legacy_public_key()
+                .map(|key| address_from_public_key(&key));
+        }
+        let sender = match &self.multisig {
+            Some(multisig) => multisig.account.address(),
+            None => self.scheme.verifier().sender(
+                &self.encode_for_hash(),
+                self.signature.as_deref().unwrap_or_default(),
+                self.public_key.as_deref(),
+            )?,
+        };
+        self.check_from(sender)
+    }
+
+    fn check_from(&self, signer: Address) -> Result<Address, String> {
+        if signer != self.from {
+            return Err("error: transaction is not signed by its sender".to_string());
         }
-        self.hash.clone().unwrap()
+        Ok(signer)
     }
 
-    pub fn sign(&mut self, private_key: &KeyPair) {
-        let sig = private_key.sign(self.encode_for_hash());
-        self.signature = Some(sig.signature.to_string());
-        self.public_key = Some(private_key.public_key.to_string());
+    // version 0 transactions are single secp256k1 signatures checked against the
+    // public key they carry. Their sender isn't committed to, so from is ignored
+    fn legacy_public_key(&self) -> Result<secp256k1::PublicKey, String> {
+        if self.multisig.is_some() || self.scheme != Scheme::Secp256k1 {
+            return Err("error: version 0 transactions are secp256k1 only".to_string());
+        }
+        if self.data.fee != 0 {
+            return Err("error: version 0 transactions carry no fee".to_string());
+        }
+        match &self.public_key {
+            Some(public_key) => new_pk_from_string(public_key.clone()),
+            None => Err("error: version 0 transaction has no public key".to_string()),
+        }
     }
 
-    pub fn verify(&self) -> Result<(), String> {
-        if self.signature == None || self.public_key == None {
-            return Err("error: signature or public key missing".to_string());
+    pub fn verify(&self, chain_id: u32) -> Result<(), String> {
+        if self.version != TX_VERSION && self.version != LEGACY_TX_VERSION {
+            return Err(format!(
+                "error: unsupported transaction version {}",
+                self.version
+            ));
+        }
+        if self.data.chain_id != chain_id {
+            return Err(format!(
+                "error: transaction is for chain {} => expected chain {}",
+                self.data.chain_id, chain_id
+            ));
         }
-        let sig_result = new_sig_from_string(self.signature.clone().unwrap())?;
-        let pk_result = new_pk_from_string(self.public_key.clone().unwrap())?;
-        if sig_result.verify(&pk_result, self.encode_for_hash()) {
+        if self.version == LEGACY_TX_VERSION {
+            let public_key = self.legacy_public_key()?;
+            let signature = match &self.signature {
+                Some(signature) => new_sig_from_string(signature.clone())?,
+                None => return Err("error: signature missing".to_string()),
+            };
+            if !signature.verify_legacy(&public_key, &self.encode_for_hash()) {
+                return Err("error: invalid signature".to_string());
+            }
             return Ok(());
         }
-        Err("error: invalid signature".to_string())
+        if let Some(multisig) = &self.multisig {
+            if self.signature.is_some() || self.public_key.is_some() {
+                return Err("error: multisig transaction has a single signature".to_string());
+            }
+            let signer = multisig.verify(&self.encode_for_hash())?;
+            return self.check_from(signer).map(|_| ());
+        }
+        let signature = match &self.signature {
+            Some(signature) => signature,
+            None => return Err("error: signature missing".to_string()),
+        };
+        let signer = self.scheme.verifier().verify(
+            &self.encode_for_hash(),
+            signature,
+            self.public_key.as_deref(),
+        )?;
+        self.check_from(signer).map(|_| ())
     }
 }
 
@@ -85,31 +278,262 @@ pub fn decode_transaction(data: String) -> Result<Transaction, String> {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::crypto::ed25519::Ed25519KeyPair;
+    use crate::crypto::keypair::{legacy_data_to_message, KeyPair};
 
     #[test]
     fn test_encode_decode_transaction() {
-        let mut t = Transaction::new([0; 20], 5);
+        let mut t = Transaction::new([0; 20], 5, 0, 0);
         println!("{}", t.hash());
 
         let mut data = t.encode();
 
         println!("{}", data.clone());
         let t_decode1 = decode_transaction(data.clone());
-        assert_eq!(t_decode1.is_ok(), true);
-        assert_eq!(t.hash() == t_decode1.unwrap().hash(), true);
+        assert!(t_decode1.is_ok());
+        assert_eq!(t.hash(), t_decode1.unwrap().hash());
 
         data += "a";
         let t_decode2 = decode_transaction(data);
-        assert_eq!(t_decode2.is_err(), true);
+        assert!(t_decode2.is_err());
     }
 
     #[test]
     fn test_sign() {
         let key_pair = KeyPair::new(0);
-        let mut t = Transaction::new([0; 20], 5);
+        let mut t = Transaction::new([0; 20], 5, 0, 0);
         println!("{}", t.hash());
 
+        assert!(t.sender().is_err());
+
+        t.sign(&key_pair);
+        assert_eq!(t.verify(0), Ok(()));
+        assert_eq!(t.sender(), Ok(key_pair.address()));
+    }
+
+    #[test]
+    fn test_fee_is_signed() {
+        let key_pair = KeyPair::new(0);
+        let mut t = Transaction::new([0; 20], 5, 0, 0).with_fee(2);
+        t.sign(&key_pair);
+        let decoded = decode_transaction(t.encode()).unwrap();
+        assert_eq!(decoded.data.fee, 2);
+        assert!(decoded.verify(0).is_ok());
+
+        let mut cheaper = decoded;
+        cheaper.data.fee = 1;
+        assert_ne!(cheaper.sender(), Ok(key_pair.address()));
+        assert!(cheaper.verify(0).is_err());
+    }
+
+    #[test]
+    fn test_sender_is_hashed() {
+        let mut a = Transaction::new([0; 20], 5, 0, 0);
+        a.sign(&KeyPair::new(0));
+        let mut b = Transaction::new([0; 20], 5, 0, 0);
+        b.sign(&KeyPair::new(1));
+        assert_ne!(a.hash(), b.hash());
+        let mut c = Transaction::new([0; 20], 5, 0, 0);
+        c.sign(&Ed25519KeyPair::new(0));
+        assert_ne!(a.hash(), c.hash());
+
+        // claiming another sender detaches the signature
+        let mut claimed = decode_transaction(a.encode()).unwrap();
+        claimed.from = KeyPair::new(1).address();
+        assert!(claimed.verify(0).is_err());
+        assert!(claimed.sender().is_err());
+    }
+
+    #[test]
+    fn test_nonce_is_signed() {
+        let key_pair = KeyPair::new(0);
+        let mut t = Transaction::new([0; 20], 5, 0, 0);
+        let hash = t.hash();
         t.sign(&key_pair);
-        assert_eq!(t.verify(), Ok(()));
+
+        let mut replay = t.clone();
+        replay.data.nonce = 1;
+        replay.hash = None;
+        // the signature no longer recovers the signer's key
+        assert_ne!(replay.sender(), Ok(key_pair.address()));
+        assert!(replay.verify(0).is_err());
+        assert_ne!(replay.hash(), hash);
+    }
+
+    // a transaction as it was encoded before versioning, signed with the public key attached
+    fn legacy_transaction(key_pair: &KeyPair, amount: u64) -> String {
+        // the fields in the order the data struct declared them
+        let data = format!(
+            r#"{{"to":{},"amount":{},"nonce":0,"chain_id":0}}"#,
+            serde_json::to_string(&[0u8; 20]).unwrap(),
+            amount
+        );
+        let message = legacy_data_to_message(data.as_bytes());
+        let signature = secp256k1::Secp256k1::new().sign(&message, &key_pair.private_key);
+        format!(
+            r#"{{"public_key":"{}","signature":"{}","data":{}}}"#,
+            key_pair.public_key, signature, data
+        )
+    }
+
+    #[test]
+    fn test_legacy_version() {
+        let key_pair = KeyPair::new(0);
+        let mut t = Transaction::new([0; 20], 5, 0, 0);
+        t.sign(&key_pair);
+
+        // transactions encoded before versioning have no version field
+        let mut legacy = decode_transaction(legacy_transaction(&key_pair, 5)).unwrap();
+        assert_eq!(legacy.version, 0);
+        assert!(legacy.verify(0).is_ok());
+        assert_eq!(legacy.sender(), Ok(key_pair.address()));
+        assert!(legacy.verify(1).is_err());
+        assert_ne!(legacy.hash(), t.hash());
+
+        let mut tampered = legacy.clone();
+        tampered.data.amount = 6;
+        assert!(tampered.verify(0).is_err());
+        let mut with_fee = legacy.clone();
+        with_fee.data.fee = 1;
+        assert!(with_fee.verify(0).is_err());
+        let mut without_key = legacy.clone();
+        without_key.public_key = None;
+        assert!(without_key.verify(0).is_err());
+        assert!(without_key.sender().is_err());
+
+        let mut future = t.clone();
+        future.version = TX_VERSION + 1;
+        assert!(future.verify(0).is_err());
+        assert!(t.verify(0).is_ok());
+    }
+
+    #[test]
+    fn test_verify_rejects_other_chain() {
+        let key_pair = KeyPair::new(0);
+        let mut t = Transaction::new([0; 20], 5, 0, 1);
+        t.sign(&key_pair);
+
+        assert!(t.verify(1).is_ok());
+        assert!(t.verify(2).is_err());
+
+        // changing the chain id detaches the signature from the signer
+        t.data.chain_id = 2;
+        assert_ne!(t.sender(), Ok(key_pair.address()));
+        assert!(t.verify(2).is_err());
+        t.public_key = Some(key_pair.public_key.to_string());
+        assert!(t.verify(2).is_err());
+    }
+
+    #[test]
+    fn test_public_key_is_recovered() {
+        let key_pair = KeyPair::new(0);
+        let mut t = Transaction::new([0; 20], 5, 0, 0);
+        t.sign(&key_pair);
+
+        // the public key is left out of the encoding
+        assert!(!t.encode().contains("public_key"));
+        let decoded = decode_transaction(t.encode()).unwrap();
+        assert!(decoded.verify(0).is_ok());
+        assert_eq!(decoded.sender(), Ok(key_pair.address()));
+
+        // a public key given along with it has to match the recovered one
+        let mut matching = decoded.clone();
+        matching.public_key = Some(key_pair.public_key.to_string());
+        assert!(matching.verify(0).is_ok());
+        let mut other = decoded.clone();
+        other.public_key = Some(KeyPair::new(1).public_key.to_string());
+        assert!(other.verify(0).is_err());
+
+        // a tampered transaction recovers some other key
+        let mut tampered = decoded;
+        tampered.data.amount = 6;
+        assert_ne!(tampered.sender(), Ok(key_pair.address()));
+        assert!(tampered.verify(0).is_err());
+    }
+
+    #[test]
+    fn test_verify_with_public_key_field() {
+        // transactions signed before recovery carry a plain signature and the public key
+        let key_pair = KeyPair::new(0);
+        let mut t = Transaction::new([0; 20], 5, 0, 0);
+        t.from = key_pair.address();
+        t.signature = Some(key_pair.sign(&t.encode_for_hash()).signature.to_string());
+        t.public_key = Some(key_pair.public_key.to_string());
+
+        let decoded = decode_transaction(t.encode()).unwrap();
+        assert!(decoded.verify(0).is_ok());
+        assert_eq!(decoded.sender(), Ok(key_pair.address()));
+
+        let mut missing_key = decoded;
+        missing_key.public_key = None;
+        assert!(missing_key.verify(0).is_err());
+        assert!(missing_key.sender().is_err());
+    }
+
+    #[test]
+    fn test_ed25519() {
+        let key_pair = Ed25519KeyPair::new(0);
+        let mut t = Transaction::new([0; 20], 5, 0, 0);
+        t.sign(&key_pair);
+        assert_eq!(t.scheme, Scheme::Ed25519);
+
+        let decoded = decode_transaction(t.encode()).unwrap();
+        assert!(decoded.verify(0).is_ok());
+        assert_eq!(decoded.sender(), Ok(key_pair.address()));
+
+        // the scheme tag picks the verifier
+        let mut wrong_scheme = decoded.clone();
+        wrong_scheme.scheme = Scheme::Secp256k1;
+        assert!(wrong_scheme.verify(0).is_err());
+
+        let mut tampered = decoded.clone();
+        tampered.data.amount = 6;
+        assert!(tampered.verify(0).is_err());
+
+        let mut missing_key = decoded;
+        missing_key.public_key = None;
+        assert!(missing_key.verify(0).is_err());
+        assert!(missing_key.sender().is_err());
+    }
+
+    #[test]
+    fn test_multisig() {
+        use crate::crypto::multisig::MultisigKey;
+
+        let keys = [KeyPair::new(0), KeyPair::new(1), KeyPair::new(2)];
+        let account =
+            MultisigAccount::new(2, keys.iter().map(|k| MultisigKey::of(k)).collect()).unwrap();
+        let mut t = Transaction::new([0; 20], 5, 0, 0);
+        t.sign_multisig(&account, &keys[0]).unwrap();
+        assert!(t.verify(0).is_err());
+        t.sign_multisig(&account, &keys[2]).unwrap();
+
+        let decoded = decode_transaction(t.encode()).unwrap();
+        assert!(decoded.verify(0).is_ok());
+        assert_eq!(decoded.sender(), Ok(account.address()));
+
+        let mut tampered = decoded.clone();
+        tampered.data.amount = 6;
+        assert!(tampered.verify(0).is_err());
+
+        // a member's signature is no single-key transaction from the member
+        let signed_by = &account.keys[t.multisig.as_ref().unwrap().signatures[0].key as usize];
+        let member = keys
+            .iter()
+            .find(|k| MultisigKey::of(*k) == *signed_by)
+            .unwrap();
+        let mut single = decoded.clone();
+        single.multisig = None;
+        single.from = member.address();
+        single.signature = Some(t.multisig.as_ref().unwrap().signatures[0].signature.clone());
+        assert!(single.verify(0).is_err());
+
+        // a single signature can't be added alongside
+        let mut mixed = decoded;
+        mixed.signature = t
+            .multisig
+            .as_ref()
+            .map(|m| m.signatures[0].signature.clone());
+        assert!(mixed.verify(0).is_err());
     }
 }
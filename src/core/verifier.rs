@@ -0,0 +1,199 @@
+use super::transaction::Transaction;
+use crate::types::hash::Hash;
+use std::collections::{HashSet, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+pub const DEFAULT_SIG_CACHE_SIZE: usize = 10_000;
+// below this many signatures per worker starting a thread costs more than it saves
+const MIN_BATCH_PER_WORKER: usize = 16;
+
+// SigCache remembers transactions whose signature has already been checked, for
+// example on entry to the mempool, so blocks including them don't check them again.
+// Clones share the same cache. When full, the oldest entry is evicted.
+#[derive(Clone)]
+pub struct SigCache {
+    entries: Arc<Mutex<CacheEntries>>,
+}
+
+struct CacheEntries {
+    verified: HashSet<Hash>,
+    // insertion order, for eviction
+    order: VecDeque<Hash>,
+    max_size: usize,
+}
+
+// the transaction hash does not cover the signature, so the key includes it, the public key,
+// the scheme they are checked with and any multisig authorization
+fn cache_key(tx: &Transaction) -> Hash {
+    let mut bytes = vec![tx.scheme.tag()];
+    bytes.extend(tx.encode_for_hash());
+    bytes.extend_from_slice(tx.signature.as_deref().unwrap_or_default().as_bytes());
+    bytes.push(b'|');
+    bytes.extend_from_slice(tx.public_key.as_deref().unwrap_or_default().as_bytes());
+    if let Some(multisig) = &tx.multisig {
+        bytes.push(b'|');
+        bytes.extend(serde_json::to_vec(multisig).unwrap());
+    }
+    Hash::digest(&bytes)
+}
+
+impl SigCache {
+    pub fn new(max_size: usize) -> Self {
+        SigCache {
+            entries: Arc::new(Mutex::new(CacheEntries {
+                verified: HashSet::new(),
+                order: VecDeque::new(),
+                max_size,
+            })),
+        }
+    }
+
+    pub fn contains(&self, tx: &Transaction) -> bool {
+        self.entries
+            .lock()
+            .unwrap()
+            .verified
+            .contains(&cache_key(tx))
+    }
+
+    pub fn insert(&self, tx: &Transaction) {
+        let key = cache_key(tx);
+        let mut entries = self.entries.lock().unwrap();
+        if entries.max_size == 0 || !entries.verified.insert(key) {
+            return;
+        }
+        entries.order.push_back(key);
+        if entries.order.len() > entries.max_size {
+            if let Some(oldest) = entries.order.pop_front() {
+                entries.verified.remove(&oldest);
+            }
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.lock().unwrap().verified.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.lock().unwrap().verified.is_empty()
+    }
+
+    // verifies a single transaction, skipping the check if it was already done
+    pub fn verify(&self, tx: &Transaction, chain_id: u32) -> Result<(), String> {
+        if self.contains(tx) {
+            return Ok(());
+        }
+        tx.verify(chain_id)?;
+        self.insert(tx);
+        Ok(())
+    }
+}
+
+// verifies the signatures of the transactions across a pool of worker threads,
+// skipping ones in the cache. On failure the error names the index of the first
+// invalid transaction; on success every transaction is added to the cache
+pub fn verify_transactions(
+    transactions: &[Transaction],
+    chain_id: u32,
+    cache: Option<&SigCache>,
+) -> Result<(), String> {
+    let pending: Vec<usize> = (0..transactions.len())
+        .filter(|i| !cache.is_some_and(|cache| cache.contains(&transactions[*i])))
+        .collect();
+
+    let verify_all = |indexes: &[usize]| -> Option<(usize, String)> {
+        indexes
+            .iter()
+            .find_map(|i| transactions[*i].verify(chain_id).err().map(|err| (*i, err)))
+    };
+
+    let workers = thread::available_parallelism()
+        .map(|n| n.get())
+        .unwrap_or(1)
+        .min(pending.len().div_ceil(MIN_BATCH_PER_WORKER))
+        .max(1);
+    let failure = if workers == 1 {
+        verify_all(&pending)
+    } else {
+        let chunk_size = pending.len().div_ceil(workers);
+        thread::scope(|s| {
+            let handles: Vec<_> = pending
+                .chunks(chunk_size)
+                .map(|chunk| s.spawn(move || verify_all(chunk)))
+                .collect();
+            handles
+                .into_iter()
+                .filter_map(|handle| handle.join().unwrap())
+                .min_by_key(|(i, _)| *i)
+        })
+    };
+
+    if let Some((i, err)) = failure {
+        return Err(format!("transaction {}: {}", i, err));
+    }
+    if let Some(cache) = cache {
+        for i in pending {
+            cache.insert(&transactions[i]);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::keypair::KeyPair;
+
+    fn signed_transactions(count: u64) -> Vec<Transaction> {
+        let key_pair = KeyPair::new(0);
+        (0..count)
+            .map(|nonce| {
+                let mut tx = Transaction::new([0; 20], 5, nonce, 0);
+                tx.sign(&key_pair);
+                tx
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_verify_reports_failing_index() {
+        let mut transactions = signed_transactions(100);
+        assert!(verify_transactions(&transactions, 0, None).is_ok());
+
+        // both invalid, the lowest index is reported whichever worker finds it first
+        transactions[80].signature = None;
+        transactions[37].signature = None;
+        let err = verify_transactions(&transactions, 0, None).unwrap_err();
+        assert!(err.starts_with("transaction 37:"), "{}", err);
+
+        let err = verify_transactions(&transactions[..2], 1, None).unwrap_err();
+        assert!(err.starts_with("transaction 0:"), "{}", err);
+    }
+
+    #[test]
+    fn test_cache() {
+        let cache = SigCache::new(DEFAULT_SIG_CACHE_SIZE);
+        let transactions = signed_transactions(3);
+        assert!(cache.verify(&transactions[0], 0).is_ok());
+        assert!(cache.contains(&transactions[0]));
+
+        assert!(verify_transactions(&transactions, 0, Some(&cache)).is_ok());
+        assert_eq!(cache.len(), 3);
+
+        // the same data with a different signature is not a cache hit
+        let mut resigned = transactions[0].clone();
+        resigned.sign(&KeyPair::new(1));
+        assert!(!cache.contains(&resigned));
+        resigned.signature = None;
+        assert!(cache.verify(&resigned, 0).is_err());
+        assert!(!cache.contains(&resigned));
+
+        let cache = SigCache::new(2);
+        for tx in &transactions {
+            cache.insert(tx);
+        }
+        assert_eq!(cache.len(), 2);
+        assert!(!cache.contains(&transactions[0]));
+    }
+}
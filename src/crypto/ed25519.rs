@@ -0,0 +1,174 @@
+use super::keypair::random_bytes;
+use super::scheme::{Scheme, Signed, Signer, Verifier};
+use crate::types::address::{address_from_key_bytes, Address};
+use ed25519_dalek::{Signature, SigningKey, VerifyingKey};
+use secp256k1::rand::{rngs, RngCore, SeedableRng};
+
+pub const PUBLIC_KEY_LEN: usize = 32;
+pub const SIGNATURE_LEN: usize = 64;
+
+pub struct Ed25519KeyPair {
+    signing_key: SigningKey,
+    pub public_key: [u8; 32],
+}
+
+impl Ed25519KeyPair {
+    // deterministic like KeyPair::new, for tests and development
+    pub fn new(seed: u64) -> Self {
+        let mut rng = rngs::StdRng::seed_from_u64(seed);
+        let mut secret = [0; 32];
+        rng.fill_bytes(&mut secret);
+        Self::from_seed(secret)
+    }
+
+    pub fn generate() -> Result<Self, String> {
+        Ok(Self::from_seed(random_bytes()?))
+    }
+
+    // the 32 byte secret key of RFC 8032
+    pub fn from_seed(seed: [u8; 32]) -> Self {
+        let signing_key = SigningKey::from_bytes(&seed);
+        Self {
+            public_key: signing_key.verifying_key().to_bytes(),
+            signing_key,
+        }
+    }
+
+    pub fn seed(&self) -> [u8; 32] {
+        self.signing_key.to_bytes()
+    }
+
+    pub fn sign_bytes(&self, data: &[u8]) -> [u8; 64] {
+        use ed25519_dalek::Signer as _;
+        self.signing_key.sign(data).to_bytes()
+    }
+}
+
+// strict verification rejects non canonical encodings, which would make signatures malleable
+pub fn verify_bytes(public_key: &[u8; 32], data: &[u8], signature: &[u8; 64]) -> bool {
+    match VerifyingKey::from_bytes(public_key) {
+        Ok(key) => key
+            .verify_strict(data, &Signature::from_bytes(signature))
+            .is_ok(),
+        Err(_) => false,
+    }
+}
+
+fn decode_hex<const N: usize>(s: &str, what: &str) -> Result<[u8; N], String> {
+    hex::decode(s)
+        .ok()
+        .and_then(|bytes| bytes.try_into().ok())
+        .ok_or(format!("error: invalid {} given", what))
+}
+
+impl Signer for Ed25519KeyPair {
+    fn scheme(&self) -> Scheme {
+        Scheme::Ed25519
+    }
+
+    fn address(&self) -> Address {
+        address_from_key_bytes(&self.public_key)
+    }
+
+    fn public_key(&self) -> String {
+        hex::encode(self.public_key)
+    }
+
+    fn sign(&self, data: &[u8]) -> Signed {
+        Signed {
+            signature: hex::encode(self.sign_bytes(data)),
+            public_key: Some(hex::encode(self.public_key)),
+        }
+    }
+}
+
+// Ed25519 keys can't be recovered from signatures, so the public key is always needed
+pub struct Ed25519Verifier;
+
+impl Verifier for Ed25519Verifier {
+    fn sender(
+        &self,
+        _data: &[u8],
+        _signature: &str,
+        public_key: Option<&str>,
+    ) -> Result<Address, String> {
+        let public_key = public_key.ok_or("error: transaction has no public key")?;
+        let public_key: [u8; PUBLIC_KEY_LEN] = decode_hex(public_key, "public key")?;
+        Ok(address_from_key_bytes(&public_key))
+    }
+
+    fn verify(
+        &self,
+        data: &[u8],
+        signature: &str,
+        public_key: Option<&str>,
+    ) -> Result<Address, String> {
+        let public_key = public_key.ok_or("error: signature or public key missing".to_string())?;
+        let public_key: [u8; PUBLIC_KEY_LEN] = decode_hex(public_key, "public key")?;
+        let signature: [u8; SIGNATURE_LEN] = decode_hex(signature, "signature")?;
+        if verify_bytes(&public_key, data, &signature) {
+            return Ok(address_from_key_bytes(&public_key));
+        }
+        Err("error: invalid signature".to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // test vectors 1 and 2 from RFC 8032 section 7.1
+    #[test]
+    fn test_rfc8032_vectors() {
+        let vectors = [
+            (
+                "9d61b19deffd5a60ba844af492ec2cc44449c5697b326919703bac031cae7f60",
+                "d75a980182b10ab7d54bfed3c964073a0ee172f3daa62325af021a68f707511a",
+                "",
+                "e5564300c360ac729086e2cc806e828a84877f1eb8e5d974d873e065224901555fb8821590a33bacc61e39701cf9b46bd25bf5f0595bbe24655141438e7a100b",
+            ),
+            (
+                "4ccd089b28ff96da9db6c346ec114e0f5b8a319f35aba624da8cf6ed4fb8a6fb",
+                "3d4017c3e843895a92b70aa74d1b7ebc9c982ccf2ec4968cc0cd55f12af4660c",
+                "72",
+                "92a009a9f0d4cab8720e820b5f642540a2b27b5416503f8fb3762223ebdb69da085ac1e43e15996e458f3613d0f11d8c387b2eaeb4302aeeb00d291612bb0c00",
+            ),
+        ];
+        for (seed, public_key, message, signature) in vectors {
+            let key_pair = Ed25519KeyPair::from_seed(decode_hex(seed, "seed").unwrap());
+            let message = hex::decode(message).unwrap();
+            assert_eq!(hex::encode(key_pair.public_key), public_key);
+            assert_eq!(hex::encode(key_pair.sign_bytes(&message)), signature);
+            assert!(verify_bytes(
+                &key_pair.public_key,
+                &message,
+                &decode_hex(signature, "signature").unwrap()
+            ));
+        }
+    }
+
+    #[test]
+    fn test_verify_failure() {
+        let key_pair = Ed25519KeyPair::new(0);
+        let signature = key_pair.sign_bytes(b"hello");
+        assert!(verify_bytes(&key_pair.public_key, b"hello", &signature));
+        assert!(!verify_bytes(&key_pair.public_key, b"hi", &signature));
+        assert!(!verify_bytes(
+            &Ed25519KeyPair::new(1).public_key,
+            b"hello",
+            &signature
+        ));
+
+        // s + L, the group order, is rejected even though it satisfies the equation
+        let l = hex::decode("edd3f55c1a631258d69cf7a2def9de1400000000000000000000000000000010")
+            .unwrap();
+        let mut malleable = signature;
+        let mut carry = 0u16;
+        for i in 0..32 {
+            let v = malleable[32 + i] as u16 + l[i] as u16 + carry;
+            malleable[32 + i] = v as u8;
+            carry = v >> 8;
+        }
+        assert!(!verify_bytes(&key_pair.public_key, b"hello", &malleable));
+    }
+}
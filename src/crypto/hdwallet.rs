@@ -0,0 +1,224 @@
+// Keys derived from a single mnemonic phrase: BIP-39 turns the phrase into a seed
+// and BIP-32 derives a tree of secp256k1 keys from it, so backing up the phrase
+// backs up every key
+use super::keypair::{random_bytes, KeyPair};
+use crate::types::address::Address;
+use bip39::Mnemonic;
+use hmac::{Hmac, Mac};
+use secp256k1::SecretKey;
+use sha2::Sha512;
+
+// indexes from here on derive hardened children, written with a ' in paths
+pub const HARDENED: u32 = 0x8000_0000;
+// the parent of the keys handed out by HdWallet::key_pair, following BIP-44
+pub const DEFAULT_ACCOUNT_PATH: &str = "m/44'/0'/0'/0";
+
+// a phrase of 12, 15, 18, 21 or 24 words from os randomness
+pub fn generate_mnemonic(word_count: usize) -> Result<String, String> {
+    if !(12..=24).contains(&word_count) || !word_count.is_multiple_of(3) {
+        return Err(format!(
+            "error: mnemonic can't have {} words => expected 12, 15, 18, 21 or 24",
+            word_count
+        ));
+    }
+    // every 3 words hold 32 bits of entropy and a checksum bit
+    let entropy: [u8; 32] = random_bytes()?;
+    Mnemonic::from_entropy(&entropy[..word_count / 3 * 4])
+        .map(|mnemonic| mnemonic.to_string())
+        .map_err(|e| format!("error: {}", e))
+}
+
+// checks the words are in the word list and the checksum matches
+pub fn validate_mnemonic(phrase: &str) -> Result<(), String> {
+    Mnemonic::parse(phrase)
+        .map(|_| ())
+        .map_err(|e| format!("error: invalid mnemonic: {}", e))
+}
+
+// parses paths like m/44'/0'/0'/0/1 into child indexes
+pub fn parse_path(path: &str) -> Result<Vec<u32>, String> {
+    let mut parts = path.split('/');
+    if parts.next() != Some("m") {
+        return Err(format!("error: derivation path {} must start with m", path));
+    }
+    parts
+        .map(|part| {
+            let (number, hardened) = match part.strip_suffix('\'') {
+                Some(number) => (number, true),
+                None => (part, false),
+            };
+            match number.parse::<u32>() {
+                Ok(index) if index < HARDENED => {
+                    Ok(if hardened { index + HARDENED } else { index })
+                }
+                _ => Err(format!("error: invalid derivation path index {}", part)),
+            }
+        })
+        .collect()
+}
+
+fn hmac_sha512(key: &[u8], data: &[u8]) -> [u8; 64] {
+    let mut mac = Hmac::<Sha512>::new_from_slice(key).unwrap();
+    mac.update(data);
+    let mut out = [0; 64];
+    out.copy_from_slice(&mac.finalize().into_bytes());
+    out
+}
+
+// a private key with the chain code needed to derive its children
+#[derive(Clone)]
+pub struct ExtendedKey {
+    key: SecretKey,
+    chain_code: [u8; 32],
+}
+
+impl ExtendedKey {
+    pub fn master(seed: &[u8]) -> Result<Self, String> {
+        Self::from_hmac(&hmac_sha512(b"Bitcoin seed", seed), None)
+    }
+
+    // the left half of i becomes the key, added to the parent's if there is one,
+    // and the right half the chain code
+    fn from_hmac(i: &[u8; 64], parent: Option<&SecretKey>) -> Result<Self, String> {
+        let mut key = SecretKey::from_slice(&i[..32])
+            .map_err(|_| "error: derived key is invalid => use the next index".to_string())?;
+        if let Some(parent) = parent {
+            key = *parent;
+            key.add_assign(&i[..32])
+                .map_err(|_| "error: derived key is invalid => use the next index".to_string())?;
+        }
+        let mut chain_code = [0; 32];
+        chain_code.copy_from_slice(&i[32..]);
+        Ok(Self { key, chain_code })
+    }
+
+    pub fn child(&self, index: u32) -> Result<Self, String> {
+        // hardened children are derived from the private key, others from the public key
+        let mut data = if index >= HARDENED {
+            let mut data = vec![0];
+            data.extend_from_slice(&self.key[..]);
+            data
+        } else {
+            self.key_pair().public_key.serialize().to_vec()
+        };
+        data.extend_from_slice(&index.to_be_bytes());
+        Self::from_hmac(&hmac_sha512(&self.chain_code, &data), Some(&self.key))
+    }
+
+    pub fn derive(&self, path: &str) -> Result<Self, String> {
+        parse_path(path)?
+            .into_iter()
+            .try_fold(self.clone(), |key, index| key.child(index))
+    }
+
+    pub fn key_pair(&self) -> KeyPair {
+        KeyPair::from_secret(&self.key[..]).unwrap()
+    }
+}
+
+pub struct HdWallet {
+    master: ExtendedKey,
+}
+
+impl HdWallet {
+    // the passphrase is optional extra protection, a different one gives different keys
+    pub fn from_mnemonic(phrase: &str, passphrase: &str) -> Result<Self, String> {
+        let mnemonic =
+            Mnemonic::parse(phrase).map_err(|e| format!("error: invalid mnemonic: {}", e))?;
+        Ok(Self {
+            master: ExtendedKey::master(&mnemonic.to_seed(passphrase))?,
+        })
+    }
+
+    pub fn derive(&self, path: &str) -> Result<KeyPair, String> {
+        Ok(self.master.derive(path)?.key_pair())
+    }
+
+    // the key at DEFAULT_ACCOUNT_PATH/index
+    pub fn key_pair(&self, index: u32) -> Result<KeyPair, String> {
+        self.derive(&format!("{}/{}", DEFAULT_ACCOUNT_PATH, index))
+    }
+
+    pub fn address(&self, index: u32) -> Result<Address, String> {
+        Ok(self.key_pair(index)?.address())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // test vector 1 from BIP-32
+    #[test]
+    fn test_bip32_vector() {
+        let master =
+            ExtendedKey::master(&hex::decode("000102030405060708090a0b0c0d0e0f").unwrap()).unwrap();
+        assert_eq!(
+            hex::encode(&master.key[..]),
+            "e8f32e723decf4051aefac8e2c93c9c5b214313817cdb01a1494b917c8436b35"
+        );
+        assert_eq!(
+            hex::encode(master.chain_code),
+            "873dff81c02f525623fd1fe5167eac3a55a049de3d314bb42ee227ffed37d508"
+        );
+
+        let child = master.derive("m/0'").unwrap();
+        assert_eq!(
+            hex::encode(&child.key[..]),
+            "edb2e14f9ee77d26dd93b4ecede8d16ed408ce149b6cd80b0715a2d911a0afea"
+        );
+        assert_eq!(
+            hex::encode(child.chain_code),
+            "47fdacbd0f1097043b78c63c20c34ef4ed9a111d980047ad16282c7ae6236141"
+        );
+        assert_eq!(
+            hex::encode(&master.derive("m/0'/1").unwrap().key[..]),
+            "3c6cb8d0f6a264c91ea8b5030fadaa8e538b020f0a387421a12de9319dc93368"
+        );
+        assert_eq!(
+            hex::encode(&master.derive("m/0'/1/2'").unwrap().key[..]),
+            "cbce0d719ecf7431d88e6a89fa1483e02e35092af60c042b1df2ff59fa424dca"
+        );
+    }
+
+    #[test]
+    fn test_mnemonic() {
+        let phrase = generate_mnemonic(24).unwrap();
+        assert_eq!(phrase.split(' ').count(), 24);
+        assert!(validate_mnemonic(&phrase).is_ok());
+        assert!(generate_mnemonic(13).is_err());
+
+        // the last word carries the checksum
+        let abandon = ["abandon"; 12].join(" ");
+        assert!(validate_mnemonic(&abandon).is_err());
+        let valid = format!("{} about", ["abandon"; 11].join(" "));
+        assert!(validate_mnemonic(&valid).is_ok());
+        assert!(validate_mnemonic(&valid.replace("about", "notaword")).is_err());
+
+        // the seed from the BIP-39 test vectors
+        let seed = Mnemonic::parse(&valid).unwrap().to_seed("TREZOR");
+        assert_eq!(hex::encode(seed), "c55257c360c07c72029aebc1b53c05ed0362ada38ead3e3e9efa3708e53495531f09a6987599d18264c1e1c92f2cf141630c7a3c4ab7c81b2f001698e7463b04");
+    }
+
+    #[test]
+    fn test_wallet() {
+        let phrase = format!("{} about", ["abandon"; 11].join(" "));
+        let wallet = HdWallet::from_mnemonic(&phrase, "").unwrap();
+        let first = wallet.key_pair(0).unwrap();
+        assert_eq!(
+            first.address(),
+            wallet.derive("m/44'/0'/0'/0/0").unwrap().address()
+        );
+        assert_ne!(wallet.address(1).unwrap(), first.address());
+
+        // the same phrase always gives the same keys, unless the passphrase differs
+        let again = HdWallet::from_mnemonic(&phrase, "").unwrap();
+        assert_eq!(again.address(0).unwrap(), first.address());
+        let other = HdWallet::from_mnemonic(&phrase, "TREZOR").unwrap();
+        assert_ne!(other.address(0).unwrap(), first.address());
+
+        assert!(parse_path("44'/0").is_err());
+        assert!(parse_path("m/x").is_err());
+        assert_eq!(parse_path("m/1/2'").unwrap(), vec![1, 2 + HARDENED]);
+    }
+}
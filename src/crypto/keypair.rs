@@ -1,17 +1,25 @@
+use super::scheme::{Scheme, Signed, Signer, Verifier};
+use crate::types::address::{address_from_public_key, Address};
 use crate::types::hash::Hash;
+use rand_core::{OsRng, RngCore};
+use secp256k1::recovery::{RecoverableSignature, RecoveryId};
 use secp256k1::All;
 use secp256k1::{
     rand::{rngs, SeedableRng},
     Message, PublicKey, Secp256k1, SecretKey, Signature,
 };
-use serde::{Deserialize, Serialize};
-use sha256::{digest, try_digest};
 use std::str::FromStr;
+use std::sync::OnceLock;
 
 pub struct KeyPair {
     pub private_key: SecretKey,
     pub public_key: PublicKey,
-    secp: Secp256k1<All>,
+}
+
+// creating a context is expensive, so one is shared by every key and signature
+fn context() -> &'static Secp256k1<All> {
+    static CONTEXT: OnceLock<Secp256k1<All>> = OnceLock::new();
+    CONTEXT.get_or_init(Secp256k1::new)
 }
 
 pub fn new_pk_from_string(public_key: String) -> Result<PublicKey, String> {
@@ -21,39 +29,80 @@ pub fn new_pk_from_string(public_key: String) -> Result<PublicKey, String> {
     }
 }
 
-fn string_to_message(data: String) -> Message {
-    let binding = digest(data);
-    let msg_hash = binding.as_bytes();
-    let mut msg_bytes: [u8; 32] = [0; 32];
-    for i in 0..32 {
-        msg_bytes[i] = msg_hash[i * 2] + msg_hash[i * 2 + 1];
+// reads from the operating system's random number generator
+pub fn random_bytes<const N: usize>() -> Result<[u8; N], String> {
+    let mut bytes = [0; N];
+    OsRng
+        .try_fill_bytes(&mut bytes)
+        .map_err(|e| format!("error: reading os randomness: {}", e))?;
+    Ok(bytes)
+}
+
+// signatures are made over the sha256 digest of the data
+fn data_to_message(data: &[u8]) -> Message {
+    Message::from_slice(Hash::digest(data).as_bytes()).unwrap()
+}
+
+// unversioned transactions were signed over the hex sha256 digest of the data,
+// with each pair of hex characters added into one byte
+pub fn legacy_data_to_message(data: &[u8]) -> Message {
+    let hex = hex::encode(Hash::digest(data).as_bytes()).into_bytes();
+    let mut bytes = [0u8; 32];
+    for (i, byte) in bytes.iter_mut().enumerate() {
+        *byte = hex[i * 2] + hex[i * 2 + 1];
     }
-    Message::from_slice(&msg_bytes).unwrap()
+    Message::from_slice(&bytes).unwrap()
 }
 
 impl KeyPair {
     pub fn new(seed: u64) -> Self {
-        let secp = Secp256k1::new();
         let mut rng = rngs::StdRng::seed_from_u64(seed);
-        let r = secp.generate_keypair(&mut rng);
+        let r = context().generate_keypair(&mut rng);
         Self {
             private_key: r.0,
             public_key: r.1,
-            secp,
         }
     }
 
-    pub fn sign(&self, data: String) -> Sig {
-        //let r2 = secp.generate_keypair(&mut rng);
-        let signature = self.secp.sign(&string_to_message(data), &self.private_key);
+    // a new key from the operating system's random number generator
+    pub fn generate() -> Result<Self, String> {
+        loop {
+            let secret: [u8; 32] = random_bytes()?;
+            // fails only for the rare values that are not below the curve order
+            if let Ok(key_pair) = Self::from_secret(&secret) {
+                return Ok(key_pair);
+            }
+        }
+    }
+
+    pub fn from_secret(secret: &[u8]) -> Result<Self, String> {
+        let private_key = SecretKey::from_slice(secret)
+            .map_err(|_| "error: invalid private key given".to_string())?;
+        Ok(Self {
+            private_key,
+            public_key: PublicKey::from_secret_key(context(), &private_key),
+        })
+    }
+
+    pub fn address(&self) -> Address {
+        address_from_public_key(&self.public_key)
+    }
+
+    pub fn sign(&self, data: &[u8]) -> Sig {
+        let signature = context().sign(&data_to_message(data), &self.private_key);
         new_sig(signature)
     }
+
+    // a signature the public key can be recovered from, so it need not be sent along
+    pub fn sign_recoverable(&self, data: &[u8]) -> RecoverableSig {
+        let signature = context().sign_recoverable(&data_to_message(data), &self.private_key);
+        RecoverableSig { signature }
+    }
 }
 
 #[derive(Debug)]
 pub struct Sig {
     pub signature: Signature,
-    secp: Secp256k1<All>,
 }
 
 pub fn new_sig_from_string(signature: String) -> Result<Sig, String> {
@@ -64,18 +113,132 @@ pub fn new_sig_from_string(signature: String) -> Result<Sig, String> {
 }
 
 pub fn new_sig(signature: Signature) -> Sig {
-    Sig {
-        signature,
-        secp: Secp256k1::new(),
-    }
+    Sig { signature }
 }
 
 impl Sig {
-    pub fn verify(&self, public_key: &PublicKey, data: String) -> bool {
-        let msg = string_to_message(data);
-        let result = self.secp.verify(&msg, &self.signature, public_key);
+    pub fn verify(&self, public_key: &PublicKey, data: &[u8]) -> bool {
+        let msg = data_to_message(data);
+        let result = context().verify(&msg, &self.signature, public_key);
         result.is_ok()
     }
+
+    pub fn verify_legacy(&self, public_key: &PublicKey, data: &[u8]) -> bool {
+        let msg = legacy_data_to_message(data);
+        context().verify(&msg, &self.signature, public_key).is_ok()
+    }
+}
+
+// the compact signature followed by the recovery id, 65 bytes in total
+pub const RECOVERABLE_SIG_LEN: usize = 65;
+
+#[derive(Debug)]
+pub struct RecoverableSig {
+    pub signature: RecoverableSignature,
+}
+
+pub fn is_recoverable_sig_string(signature: &str) -> bool {
+    signature.len() == RECOVERABLE_SIG_LEN * 2
+}
+
+pub fn new_recoverable_sig_from_string(signature: &str) -> Result<RecoverableSig, String> {
+    let bytes = match hex::decode(signature) {
+        Ok(v) if v.len() == RECOVERABLE_SIG_LEN => v,
+        _ => return Err("error: invalid signature given".to_string()),
+    };
+    let signature = RecoveryId::from_i32(bytes[64] as i32)
+        .and_then(|id| RecoverableSignature::from_compact(&bytes[..64], id))
+        .map_err(|_| "error: invalid signature given".to_string())?;
+    Ok(RecoverableSig { signature })
+}
+
+impl RecoverableSig {
+    // returns the key that signed the data. Any valid signature recovers some key,
+    // so the caller has to check it is the expected one
+    pub fn recover(&self, data: &[u8]) -> Result<PublicKey, String> {
+        context()
+            .recover(&data_to_message(data), &self.signature)
+            .map_err(|_| "error: invalid signature".to_string())
+    }
+}
+
+impl std::fmt::Display for RecoverableSig {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let (id, compact) = self.signature.serialize_compact();
+        let mut bytes = compact.to_vec();
+        bytes.push(id.to_i32() as u8);
+        write!(f, "{}", hex::encode(bytes))
+    }
+}
+
+// signs with recoverable signatures, so the public key is left out
+impl Signer for KeyPair {
+    fn scheme(&self) -> Scheme {
+        Scheme::Secp256k1
+    }
+
+    fn address(&self) -> Address {
+        KeyPair::address(self)
+    }
+
+    fn public_key(&self) -> String {
+        self.public_key.to_string()
+    }
+
+    fn sign(&self, data: &[u8]) -> Signed {
+        Signed {
+            signature: self.sign_recoverable(data).to_string(),
+            public_key: None,
+        }
+    }
+}
+
+// Recoverable signatures carry the key, older ones need the public key to be given
+pub struct Secp256k1Verifier;
+
+impl Verifier for Secp256k1Verifier {
+    fn sender(
+        &self,
+        data: &[u8],
+        signature: &str,
+        public_key: Option<&str>,
+    ) -> Result<Address, String> {
+        match public_key {
+            Some(public_key) => Ok(address_from_public_key(&new_pk_from_string(
+                public_key.to_string(),
+            )?)),
+            None if is_recoverable_sig_string(signature) => Ok(address_from_public_key(
+                &new_recoverable_sig_from_string(signature)?.recover(data)?,
+            )),
+            None => Err("error: transaction has no public key".to_string()),
+        }
+    }
+
+    fn verify(
+        &self,
+        data: &[u8],
+        signature: &str,
+        public_key: Option<&str>,
+    ) -> Result<Address, String> {
+        if is_recoverable_sig_string(signature) {
+            let recovered = new_recoverable_sig_from_string(signature)?.recover(data)?;
+            if let Some(public_key) = public_key {
+                if new_pk_from_string(public_key.to_string())? != recovered {
+                    return Err("error: invalid signature".to_string());
+                }
+            }
+            return Ok(address_from_public_key(&recovered));
+        }
+
+        let public_key = match public_key {
+            Some(public_key) => new_pk_from_string(public_key.to_string())?,
+            None => return Err("error: signature or public key missing".to_string()),
+        };
+        if new_sig_from_string(signature.to_string())?.verify(&public_key, data) {
+            return Ok(address_from_public_key(&public_key));
+        }
+        Err("error: invalid signature".to_string())
+    }
 }
 
 #[cfg(test)]
@@ -85,33 +248,50 @@ mod tests {
     #[test]
     fn test_sign_verify_success() {
         let keypair = KeyPair::new(0);
-        let msg = "hello".to_string();
+        let msg = b"hello";
 
-        let sig = keypair.sign(msg.clone());
-        assert_eq!(sig.verify(&keypair.public_key, msg), true);
+        let sig = keypair.sign(msg);
+        assert!(sig.verify(&keypair.public_key, msg));
     }
 
     #[test]
     fn test_sign_verify_failure() {
         let keypair = KeyPair::new(1);
         println!("{:?}", keypair.private_key.to_string());
-        let msg = "hello".to_string();
+        let msg = b"hello";
 
-        let sig = keypair.sign(msg.clone());
-        assert_eq!(sig.verify(&keypair.public_key, "hi".to_string()), false);
+        let sig = keypair.sign(msg);
+        assert!(!sig.verify(&keypair.public_key, b"hi"));
 
         let other_keypair = KeyPair::new(2);
         println!("{:?}", other_keypair.private_key.to_string());
+        assert!(!sig.verify(&other_keypair.public_key, b"hello"));
+    }
+
+    #[test]
+    fn test_data_to_message() {
+        // the message is the full sha256 digest of the data
+        let m = data_to_message(b"hello");
         assert_eq!(
-            sig.verify(&other_keypair.public_key, "hello".to_string()),
-            false
+            m.as_ref(),
+            Hash::from_str("2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824")
+                .unwrap()
+                .as_bytes()
         );
     }
 
     #[test]
-    fn test_string_to_message() {
-        let s = digest("hello");
-        let m = string_to_message(s.clone());
-        //assert_eq!(s, s2);
+    fn test_recover() {
+        let keypair = KeyPair::new(0);
+        let sig = keypair.sign_recoverable(b"hello");
+        let encoded = sig.to_string();
+        assert!(is_recoverable_sig_string(&encoded));
+
+        let decoded = new_recoverable_sig_from_string(&encoded).unwrap();
+        assert_eq!(decoded.recover(b"hello"), Ok(keypair.public_key));
+        assert_ne!(decoded.recover(b"hi"), Ok(keypair.public_key));
+
+        assert!(new_recoverable_sig_from_string(&encoded[2..]).is_err());
+        assert!(new_recoverable_sig_from_string(&format!("{}05", &encoded[..128])).is_err());
     }
 }
@@ -0,0 +1,375 @@
+use super::ed25519::Ed25519KeyPair;
+use super::keypair::{random_bytes, KeyPair};
+use super::scheme::{Scheme, Signer};
+use crate::types::address::{decode_address, encode_address, Address};
+use aes_gcm::aead::{Aead, KeyInit, Payload};
+use aes_gcm::{Aes256Gcm, Nonce};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use zeroize::Zeroizing;
+
+pub const KEYSTORE_VERSION: u32 = 1;
+// scrypt with n = 2^15 and r = 8 uses 32 MiB per key derivation
+pub const DEFAULT_SCRYPT_LOG_N: u8 = 15;
+// key files asking for more than 1 GiB per derivation are refused, so a crafted
+// file can't exhaust memory or stall the node
+pub const MAX_SCRYPT_LOG_N: u8 = 20;
+const SCRYPT_R: u32 = 8;
+const SCRYPT_P: u32 = 1;
+
+const KDF: &str = "scrypt";
+const CIPHER: &str = "aes-256-gcm";
+
+// A key file holds one secret, encrypted with AES-256-GCM under a key derived from
+// a passphrase with scrypt. The address is authenticated along with the secret, so
+// a key file can't be passed off as another's.
+#[derive(Serialize, Deserialize)]
+struct KeyFile {
+    version: u32,
+    address: String,
+    scheme: Scheme,
+    crypto: Crypto,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Crypto {
+    kdf: String,
+    kdfparams: KdfParams,
+    cipher: String,
+    nonce: String,
+    ciphertext: String,
+}
+
+#[derive(Serialize, Deserialize)]
+struct KdfParams {
+    log_n: u8,
+    r: u32,
+    p: u32,
+    salt: String,
+}
+
+// only the parameters this keystore writes are accepted, with a cost of at most MAX_SCRYPT_LOG_N
+fn derive_key(
+    passphrase: &str,
+    params: &KdfParams,
+    salt: &[u8],
+) -> Result<Zeroizing<[u8; 32]>, String> {
+    if params.log_n > MAX_SCRYPT_LOG_N || params.r != SCRYPT_R || params.p != SCRYPT_P {
+        return Err(format!(
+            "error: unsupported scrypt parameters log_n {}, r {}, p {}",
+            params.log_n, params.r, params.p
+        ));
+    }
+    let scrypt_params = scrypt::Params::new(params.log_n, params.r, params.p, 32)
+        .map_err(|_| "error: key file has invalid scrypt parameters".to_string())?;
+    let mut key = Zeroizing::new([0; 32]);
+    scrypt::scrypt(passphrase.as_bytes(), salt, &scrypt_params, &mut *key)
+        .map_err(|_| "error: deriving key file key".to_string())?;
+    Ok(key)
+}
+
+fn decode_field(value: &str, name: &str) -> Result<Vec<u8>, String> {
+    hex::decode(value).map_err(|_| format!("error: key file has an invalid {}", name))
+}
+
+pub fn signer_from_secret(scheme: Scheme, secret: &[u8]) -> Result<Box<dyn Signer>, String> {
+    match scheme {
+        Scheme::Secp256k1 => Ok(Box::new(KeyPair::from_secret(secret)?)),
+        Scheme::Ed25519 => {
+            let seed: [u8; 32] = secret
+                .try_into()
+                .map_err(|_| "error: invalid private key given".to_string())?;
+            Ok(Box::new(Ed25519KeyPair::from_seed(seed)))
+        }
+    }
+}
+
+// Keystore keeps validator and wallet keys in a directory, one encrypted file per
+// key named after its address
+pub struct Keystore {
+    dir: PathBuf,
+    log_n: u8,
+}
+
+impl Keystore {
+    pub fn open(dir: impl AsRef<Path>) -> Result<Self, String> {
+        Self::open_with_scrypt_cost(dir, DEFAULT_SCRYPT_LOG_N)
+    }
+
+    // the cost applies to keys written from now on, each file records its own
+    pub fn open_with_scrypt_cost(dir: impl AsRef<Path>, log_n: u8) -> Result<Self, String> {
+        let dir = dir.as_ref().to_path_buf();
+        fs::create_dir_all(&dir).map_err(|e| format!("error: creating keystore: {}", e))?;
+        Ok(Self { dir, log_n })
+    }
+
+    // creates a key from os randomness and returns its address
+    pub fn generate(&self, scheme: Scheme, passphrase: &str) -> Result<Address, String> {
+        let secret = Zeroizing::new(match scheme {
+            Scheme::Secp256k1 => KeyPair::generate()?.private_key[..].to_vec(),
+            Scheme::Ed25519 => Ed25519KeyPair::generate()?.seed().to_vec(),
+        });
+        self.import(scheme, &secret, passphrase)
+    }
+
+    // stores an existing secret key, or an Ed25519 seed, under the passphrase
+    pub fn import(
+        &self,
+        scheme: Scheme,
+        secret: &[u8],
+        passphrase: &str,
+    ) -> Result<Address, String> {
+        let address = signer_from_secret(scheme, secret)?.address();
+        let path = self.path(&address);
+        if path.exists() {
+            return Err(format!(
+                "error: key {} is already in the keystore",
+                encode_address(&address)
+            ));
+        }
+
+        let salt: [u8; 32] = random_bytes()?;
+        let nonce: [u8; 12] = random_bytes()?;
+        let kdfparams = KdfParams {
+            log_n: self.log_n,
+            r: SCRYPT_R,
+            p: SCRYPT_P,
+            salt: hex::encode(salt),
+        };
+        let key = derive_key(passphrase, &kdfparams, &salt)?;
+        let payload = Payload {
+            msg: secret,
+            aad: &address,
+        };
+        let ciphertext = Aes256Gcm::new((&*key).into())
+            .encrypt(Nonce::from_slice(&nonce), payload)
+            .map_err(|_| "error: encrypting key".to_string())?;
+        let key_file = KeyFile {
+            version: KEYSTORE_VERSION,
+            address: encode_address(&address),
+            scheme,
+            crypto: Crypto {
+                kdf: KDF.to_string(),
+                kdfparams,
+                cipher: CIPHER.to_string(),
+                nonce: hex::encode(nonce),
+                ciphertext: hex::encode(ciphertext),
+            },
+        };
+
+        // written to a temporary file first so a crash never leaves a partial key file
+        let tmp = path.with_extension("tmp");
+        let json = serde_json::to_string_pretty(&key_file).unwrap();
+        create_private(&tmp)
+            .and_then(|mut f| {
+                f.write_all(json.as_bytes())?;
+                f.sync_all()
+            })
+            .and_then(|_| fs::rename(&tmp, &path))
+            .map_err(|e| format!("error: writing key file: {}", e))?;
+        Ok(address)
+    }
+
+    // returns the decrypted secret, for backups or moving the key to another keystore.
+    // It is wiped from memory when dropped
+    pub fn export(
+        &self,
+        address: &Address,
+        passphrase: &str,
+    ) -> Result<Zeroizing<Vec<u8>>, String> {
+        let key_file = self.read(address)?;
+        let crypto = &key_file.crypto;
+        if crypto.kdf != KDF || crypto.cipher != CIPHER {
+            return Err(format!(
+                "error: unsupported key file encryption {} with {}",
+                crypto.cipher, crypto.kdf
+            ));
+        }
+        let salt = decode_field(&crypto.kdfparams.salt, "salt")?;
+        let nonce = decode_field(&crypto.nonce, "nonce")?;
+        let ciphertext = decode_field(&crypto.ciphertext, "ciphertext")?;
+        if nonce.len() != 12 {
+            return Err("error: key file has an invalid nonce".to_string());
+        }
+
+        let key = derive_key(passphrase, &crypto.kdfparams, &salt)?;
+        let payload = Payload {
+            msg: &ciphertext,
+            aad: address,
+        };
+        Aes256Gcm::new((&*key).into())
+            .decrypt(Nonce::from_slice(&nonce), payload)
+            .map(Zeroizing::new)
+            .map_err(|_| "error: wrong passphrase or corrupted key file".to_string())
+    }
+
+    pub fn load(&self, address: &Address, passphrase: &str) -> Result<Box<dyn Signer>, String> {
+        let scheme = self.read(address)?.scheme;
+        let signer = signer_from_secret(scheme, &self.export(address, passphrase)?)?;
+        if signer.address() != *address {
+            return Err("error: key file does not match its address".to_string());
+        }
+        Ok(signer)
+    }
+
+    // the address and scheme of every key, sorted by address. Needs no passphrase
+    pub fn list(&self) -> Result<Vec<(Address, Scheme)>, String> {
+        let entries =
+            fs::read_dir(&self.dir).map_err(|e| format!("error: reading keystore: {}", e))?;
+        let mut keys = vec![];
+        for entry in entries {
+            let path = entry
+                .map_err(|e| format!("error: reading keystore: {}", e))?
+                .path();
+            if path.extension().is_some_and(|ext| ext == "json") {
+                let key_file = read_key_file(&path)?;
+                keys.push((decode_address(&key_file.address)?, key_file.scheme));
+            }
+        }
+        keys.sort_by_key(|(address, _)| *address);
+        Ok(keys)
+    }
+
+    fn path(&self, address: &Address) -> PathBuf {
+        self.dir.join(format!("{}.json", encode_address(address)))
+    }
+
+    fn read(&self, address: &Address) -> Result<KeyFile, String> {
+        let path = self.path(address);
+        if !path.exists() {
+            return Err(format!(
+                "error: key {} is not in the keystore",
+                encode_address(address)
+            ));
+        }
+        read_key_file(&path)
+    }
+}
+
+fn read_key_file(path: &Path) -> Result<KeyFile, String> {
+    let data = fs::read_to_string(path).map_err(|e| format!("error: reading key file: {}", e))?;
+    let key_file: KeyFile = serde_json::from_str(&data)
+        .map_err(|_| format!("error: invalid key file {}", path.display()))?;
+    if key_file.version != KEYSTORE_VERSION {
+        return Err(format!(
+            "error: unsupported key file version {}",
+            key_file.version
+        ));
+    }
+    Ok(key_file)
+}
+
+// key files are only readable by their owner. A temporary file left by an
+// earlier crash is replaced so it can't keep looser permissions
+fn create_private(path: &Path) -> std::io::Result<fs::File> {
+    let _ = fs::remove_file(path);
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    options.open(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!(
+            "blockchain-keystore-{}-{}",
+            name,
+            std::process::id()
+        ));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    #[test]
+    fn test_generate_load() {
+        let keystore = Keystore::open_with_scrypt_cost(temp_dir("generate"), 4).unwrap();
+        let secp = keystore.generate(Scheme::Secp256k1, "secret").unwrap();
+        let ed = keystore.generate(Scheme::Ed25519, "other").unwrap();
+
+        let mut expected = vec![(secp, Scheme::Secp256k1), (ed, Scheme::Ed25519)];
+        expected.sort_by_key(|(address, _)| *address);
+        assert_eq!(keystore.list().unwrap(), expected);
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = fs::metadata(keystore.path(&secp))
+                .unwrap()
+                .permissions()
+                .mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+
+        let signer = keystore.load(&secp, "secret").unwrap();
+        assert_eq!(signer.address(), secp);
+        assert_eq!(signer.scheme(), Scheme::Secp256k1);
+        assert_eq!(keystore.load(&ed, "other").unwrap().address(), ed);
+
+        assert!(keystore.load(&secp, "wrong").is_err());
+        assert!(keystore.load(&[0; 20], "secret").is_err());
+    }
+
+    #[test]
+    fn test_import_export() {
+        let dir = temp_dir("import");
+        let keystore = Keystore::open_with_scrypt_cost(&dir, 4).unwrap();
+        let key_pair = KeyPair::new(0);
+        let address = keystore
+            .import(Scheme::Secp256k1, &key_pair.private_key[..], "secret")
+            .unwrap();
+        assert_eq!(address, key_pair.address());
+        assert!(keystore
+            .import(Scheme::Secp256k1, &key_pair.private_key[..], "secret")
+            .is_err());
+
+        // the secret is not stored in the clear
+        let json = fs::read_to_string(keystore.path(&address)).unwrap();
+        assert!(!json.contains(&hex::encode(&key_pair.private_key[..])));
+
+        let secret = keystore.export(&address, "secret").unwrap();
+        assert_eq!(*secret, key_pair.private_key[..].to_vec());
+
+        // a tampered ciphertext fails authentication
+        let mut key_file: KeyFile = serde_json::from_str(&json).unwrap();
+        key_file.crypto.ciphertext = hex::encode(vec![0; 48]);
+        fs::write(
+            keystore.path(&address),
+            serde_json::to_string(&key_file).unwrap(),
+        )
+        .unwrap();
+        assert!(keystore.export(&address, "secret").is_err());
+
+        // so do scrypt parameters the keystore never writes
+        for (log_n, r, p) in [
+            (MAX_SCRYPT_LOG_N + 1, SCRYPT_R, SCRYPT_P),
+            (4, 1, 1),
+            (4, 8, 2),
+        ] {
+            let mut key_file: KeyFile = serde_json::from_str(&json).unwrap();
+            key_file.crypto.kdfparams.log_n = log_n;
+            key_file.crypto.kdfparams.r = r;
+            key_file.crypto.kdfparams.p = p;
+            fs::write(
+                keystore.path(&address),
+                serde_json::to_string(&key_file).unwrap(),
+            )
+            .unwrap();
+            let err = keystore.export(&address, "secret").unwrap_err();
+            assert!(err.contains("unsupported scrypt parameters"));
+        }
+
+        key_file.version = KEYSTORE_VERSION + 1;
+        fs::write(
+            keystore.path(&address),
+            serde_json::to_string(&key_file).unwrap(),
+        )
+        .unwrap();
+        assert!(keystore.list().is_err());
+    }
+}
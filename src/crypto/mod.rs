@@ -1 +1,6 @@
+pub mod ed25519;
+pub mod hdwallet;
 pub mod keypair;
+pub mod keystore;
+pub mod multisig;
+pub mod scheme;
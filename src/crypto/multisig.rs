@@ -0,0 +1,301 @@
+use super::keypair::new_pk_from_string;
+use super::scheme::{Scheme, Signer};
+use crate::types::address::{address_from_key_bytes, Address};
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+
+pub const MAX_MULTISIG_KEYS: usize = 16;
+// prefixes what the keys of a multisig account sign
+const MULTISIG_DOMAIN: &[u8] = b"multisig signature";
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct MultisigKey {
+    pub scheme: Scheme,
+    // hex, as in the public_key field of a transaction
+    pub public_key: String,
+}
+
+impl MultisigKey {
+    pub fn of(signer: &dyn Signer) -> Self {
+        MultisigKey {
+            scheme: signer.scheme(),
+            public_key: signer.public_key(),
+        }
+    }
+
+    // keys must be in their canonical encoding, otherwise one key could be listed
+    // twice in different forms and count as two signers
+    fn validate(&self) -> Result<(), String> {
+        let canonical = match self.scheme {
+            Scheme::Secp256k1 => new_pk_from_string(self.public_key.clone())?.to_string(),
+            Scheme::Ed25519 => match hex::decode(&self.public_key) {
+                Ok(bytes) if bytes.len() == 32 => hex::encode(bytes),
+                _ => return Err("error: invalid public key given".to_string()),
+            },
+        };
+        if canonical != self.public_key {
+            return Err(format!(
+                "error: multisig key {} is not in canonical form",
+                self.public_key
+            ));
+        }
+        Ok(())
+    }
+}
+
+// An account controlled by any threshold of its keys. The keys are kept sorted so
+// the same set always gives the same address.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct MultisigAccount {
+    pub threshold: u8,
+    pub keys: Vec<MultisigKey>,
+}
+
+impl MultisigAccount {
+    pub fn new(threshold: u8, mut keys: Vec<MultisigKey>) -> Result<Self, String> {
+        keys.sort();
+        let account = MultisigAccount { threshold, keys };
+        account.validate()?;
+        Ok(account)
+    }
+
+    // accounts decoded from the network must be checked before use
+    pub fn validate(&self) -> Result<(), String> {
+        if self.keys.is_empty() || self.keys.len() > MAX_MULTISIG_KEYS {
+            return Err(format!(
+                "error: multisig account has {} keys => expected 1 to {}",
+                self.keys.len(),
+                MAX_MULTISIG_KEYS
+            ));
+        }
+        if self.threshold == 0 || self.threshold as usize > self.keys.len() {
+            return Err(format!(
+                "error: multisig threshold {} is not between 1 and {}",
+                self.threshold,
+                self.keys.len()
+            ));
+        }
+        if self.keys.windows(2).any(|pair| pair[0] >= pair[1]) {
+            return Err("error: multisig keys must be sorted and distinct".to_string());
+        }
+        for key in &self.keys {
+            key.validate()?;
+        }
+        Ok(())
+    }
+
+    // commits to the threshold and every key with its scheme
+    pub fn address(&self) -> Address {
+        let mut bytes = b"multisig".to_vec();
+        bytes.push(self.threshold);
+        bytes.push(self.keys.len() as u8);
+        for key in &self.keys {
+            bytes.push(key.scheme.tag());
+            bytes.extend_from_slice(&(key.public_key.len() as u32).to_le_bytes());
+            bytes.extend_from_slice(key.public_key.as_bytes());
+        }
+        address_from_key_bytes(&bytes)
+    }
+
+    fn index_of(&self, key: &MultisigKey) -> Option<usize> {
+        self.keys.iter().position(|k| k == key)
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct MultisigSignature {
+    // position of the signing key in the account
+    pub key: u8,
+    pub signature: String,
+}
+
+// the authorization of a transaction sent from a multisig account
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct Multisig {
+    pub account: MultisigAccount,
+    pub signatures: Vec<MultisigSignature>,
+}
+
+impl Multisig {
+    pub fn new(account: MultisigAccount) -> Self {
+        Multisig {
+            account,
+            signatures: vec![],
+        }
+    }
+
+    // signing again with a key replaces its earlier signature
+    pub fn sign(&mut self, signer: &dyn Signer, data: &[u8]) -> Result<(), String> {
+        let index = self
+            .account
+            .index_of(&MultisigKey::of(signer))
+            .ok_or("error: signer is not a key of the multisig account")?;
+        let signature = MultisigSignature {
+            key: index as u8,
+            signature: signer.sign(&self.preimage(data)).signature,
+        };
+        self.signatures.retain(|s| s.key != signature.key);
+        self.signatures.push(signature);
+        Ok(())
+    }
+
+    // checks every signature and that enough distinct keys signed, returning the
+    // account's address
+    pub fn verify(&self, data: &[u8]) -> Result<Address, String> {
+        self.account.validate()?;
+        if self.signatures.len() > self.account.keys.len() {
+            return Err("error: more multisig signatures than keys".to_string());
+        }
+
+        let preimage = self.preimage(data);
+        let mut signers = HashSet::new();
+        for signature in &self.signatures {
+            let key = self
+                .account
+                .keys
+                .get(signature.key as usize)
+                .ok_or(format!("error: multisig has no key {}", signature.key))?;
+            key.scheme
+                .verifier()
+                .verify(&preimage, &signature.signature, Some(&key.public_key))
+                .map_err(|e| format!("key {}: {}", signature.key, e))?;
+            signers.insert(signature.key);
+        }
+
+        if signers.len() < self.account.threshold as usize {
+            return Err(format!(
+                "error: {} of {} required multisig signatures",
+                signers.len(),
+                self.account.threshold
+            ));
+        }
+        Ok(self.account.address())
+    }
+
+    // binds the data to the account, so a key's signature is neither a valid
+    // signature of its own account nor of another multisig account holding the key
+    fn preimage(&self, data: &[u8]) -> Vec<u8> {
+        let mut bytes = MULTISIG_DOMAIN.to_vec();
+        bytes.extend_from_slice(&self.account.address());
+        bytes.extend_from_slice(data);
+        bytes
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::ed25519::Ed25519KeyPair;
+    use crate::crypto::keypair::KeyPair;
+
+    #[test]
+    fn test_account() {
+        let a = KeyPair::new(0);
+        let b = Ed25519KeyPair::new(1);
+        let c = KeyPair::new(2);
+        let keys = vec![
+            MultisigKey::of(&a),
+            MultisigKey::of(&b),
+            MultisigKey::of(&c),
+        ];
+
+        // the order keys are given in doesn't change the address
+        let account = MultisigAccount::new(2, keys.clone()).unwrap();
+        let mut reversed = keys.clone();
+        reversed.reverse();
+        assert_eq!(
+            MultisigAccount::new(2, reversed).unwrap().address(),
+            account.address()
+        );
+        // but the threshold does
+        assert_ne!(
+            MultisigAccount::new(1, keys.clone()).unwrap().address(),
+            account.address()
+        );
+
+        assert!(MultisigAccount::new(0, keys.clone()).is_err());
+        assert!(MultisigAccount::new(4, keys.clone()).is_err());
+        assert!(MultisigAccount::new(1, vec![keys[0].clone(), keys[0].clone()]).is_err());
+        let bad_key = MultisigKey {
+            scheme: Scheme::Secp256k1,
+            public_key: "00".to_string(),
+        };
+        assert!(MultisigAccount::new(1, vec![bad_key]).is_err());
+        let upper_case = MultisigKey {
+            scheme: Scheme::Ed25519,
+            public_key: keys[1].public_key.to_uppercase(),
+        };
+        assert!(MultisigAccount::new(1, vec![keys[1].clone(), upper_case]).is_err());
+    }
+
+    #[test]
+    fn test_verify() {
+        let a = KeyPair::new(0);
+        let b = Ed25519KeyPair::new(1);
+        let c = KeyPair::new(2);
+        let account = MultisigAccount::new(
+            2,
+            vec![
+                MultisigKey::of(&a),
+                MultisigKey::of(&b),
+                MultisigKey::of(&c),
+            ],
+        )
+        .unwrap();
+
+        let mut multisig = Multisig::new(account.clone());
+        multisig.sign(&a, b"data").unwrap();
+        assert!(multisig.verify(b"data").is_err());
+
+        // signing twice with one key still counts once
+        multisig.sign(&a, b"data").unwrap();
+        assert_eq!(multisig.signatures.len(), 1);
+        let mut duplicated = multisig.clone();
+        duplicated.signatures.push(multisig.signatures[0].clone());
+        assert!(duplicated.verify(b"data").is_err());
+
+        multisig.sign(&b, b"data").unwrap();
+        assert_eq!(multisig.verify(b"data"), Ok(account.address()));
+        assert!(multisig.verify(b"other").is_err());
+
+        // a signature made by one key can't be counted for another
+        let mut swapped = multisig.clone();
+        let c_index = account.index_of(&MultisigKey::of(&c)).unwrap() as u8;
+        swapped.signatures[0].key = c_index;
+        assert!(swapped.verify(b"data").is_err());
+
+        assert!(multisig.sign(&KeyPair::new(3), b"data").is_err());
+    }
+
+    #[test]
+    fn test_signatures_are_bound_to_the_account() {
+        let a = KeyPair::new(0);
+        let b = KeyPair::new(1);
+        let account =
+            MultisigAccount::new(1, vec![MultisigKey::of(&a), MultisigKey::of(&b)]).unwrap();
+        let mut multisig = Multisig::new(account);
+        multisig.sign(&a, b"data").unwrap();
+        assert!(multisig.verify(b"data").is_ok());
+
+        // not a signature of the data by the key alone
+        let signature = &multisig.signatures[0].signature;
+        let public_key = a.public_key.to_string();
+        let verifier = Scheme::Secp256k1.verifier();
+        assert!(verifier
+            .verify(b"data", signature, Some(&public_key))
+            .is_err());
+
+        // nor by another account holding the same key at the same position
+        let other = MultisigAccount::new(
+            1,
+            vec![MultisigKey::of(&a), MultisigKey::of(&KeyPair::new(2))],
+        )
+        .unwrap();
+        assert_eq!(other.index_of(&MultisigKey::of(&a)), Some(0));
+        let replayed = Multisig {
+            account: other,
+            signatures: multisig.signatures.clone(),
+        };
+        assert!(replayed.verify(b"data").is_err());
+    }
+}
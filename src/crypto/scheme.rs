@@ -0,0 +1,63 @@
+use super::ed25519::Ed25519Verifier;
+use super::keypair::Secp256k1Verifier;
+use crate::types::address::Address;
+use serde::{Deserialize, Serialize};
+
+// the signature scheme of a transaction, carried in its envelope so accounts of
+// both kinds can send transactions on the same chain
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Scheme {
+    #[default]
+    Secp256k1,
+    Ed25519,
+}
+
+impl Scheme {
+    pub fn tag(self) -> u8 {
+        match self {
+            Scheme::Secp256k1 => 0,
+            Scheme::Ed25519 => 1,
+        }
+    }
+
+    pub fn verifier(self) -> &'static dyn Verifier {
+        match self {
+            Scheme::Secp256k1 => &Secp256k1Verifier,
+            Scheme::Ed25519 => &Ed25519Verifier,
+        }
+    }
+}
+
+// a hex signature and, if it can't be recovered from the signature, the hex public key
+pub struct Signed {
+    pub signature: String,
+    pub public_key: Option<String>,
+}
+
+// anything that holds a key, in memory or in signing hardware
+pub trait Signer: Send + Sync {
+    fn scheme(&self) -> Scheme;
+    fn address(&self) -> Address;
+    // hex, as in the public_key field of a transaction
+    fn public_key(&self) -> String;
+    fn sign(&self, data: &[u8]) -> Signed;
+}
+
+pub trait Verifier: Send + Sync {
+    // the address of the signer. The signature is not checked when the public key is given
+    fn sender(
+        &self,
+        data: &[u8],
+        signature: &str,
+        public_key: Option<&str>,
+    ) -> Result<Address, String>;
+
+    // checks the signature and returns the address of the key that made it
+    fn verify(
+        &self,
+        data: &[u8],
+        signature: &str,
+        public_key: Option<&str>,
+    ) -> Result<Address, String>;
+}
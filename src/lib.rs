@@ -0,0 +1,4 @@
+pub mod core;
+pub mod crypto;
+pub mod network;
+pub mod types;
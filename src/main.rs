@@ -1,48 +1,96 @@
-use std::{
-    io::{Read, Write},
-    net::{TcpListener, TcpStream},
-    thread,
-    time::Duration,
-};
+use std::{env, net::TcpStream, process, thread, time::Duration};
 
-use crate::{
-    crypto::keypair::KeyPair,
-    network::server::{Server, ServerOpts},
+use blockchain::{
+    crypto::{keystore::Keystore, scheme::Scheme},
+    network::server::{Server, ServerOpts, ValidatorKey},
 };
 
-mod core;
-mod crypto;
-mod network;
-mod types;
-
 fn main() {
+    let validator_key = validator_key().unwrap_or_else(|err| exit_with(&err));
+    // the validator starts out with funds to send and is the only one allowed to sign blocks
+    let genesis_allocations = vec![(validator_key.address(), 1_000_000)];
+    let validators = vec![validator_key.address()];
     let mut local = Server::new(ServerOpts {
         listen_addr: "3000".to_string(),
-        key_pair: Some(KeyPair::new(0)),
+        chain_id: 1,
+        data_dir: Some(data_dir("3000")),
+        validator_key: Some(validator_key),
         block_time: 3,
-        seed_nodes: vec![String::from(":4000")],
+        seed_nodes: vec![],
         rpc_decode_func: None,
-    });
+        max_frame_size: None,
+        block_limits: None,
+        max_pool_size: None,
+        max_pool_per_sender: None,
+        genesis_allocations: genesis_allocations.clone(),
+        validators: validators.clone(),
+    })
+    .unwrap_or_else(|err| exit_with(&err));
 
     let mut remote = Server::new(ServerOpts {
         listen_addr: "4000".to_string(),
-        key_pair: None,
+        chain_id: 1,
+        data_dir: Some(data_dir("4000")),
+        validator_key: None,
         block_time: 3,
-        seed_nodes: vec![],
+        seed_nodes: vec![String::from(":3000")],
         rpc_decode_func: None,
-    });
+        max_frame_size: None,
+        block_limits: None,
+        max_pool_size: None,
+        max_pool_per_sender: None,
+        genesis_allocations,
+        validators,
+    })
+    .unwrap_or_else(|err| exit_with(&err));
 
     thread::spawn(move || {
-        local.start();
+        if let Err(err) = local.start() {
+            exit_with(&err);
+        }
     });
 
+    // remote joins once local has produced a few blocks, and syncs them
     thread::spawn(move || {
-        remote.start();
+        thread::sleep(Duration::from_secs(10));
+        if let Err(err) = remote.start() {
+            exit_with(&err);
+        }
     });
 
     thread::sleep(Duration::from_secs(1));
-    let mut stream = TcpStream::connect("localhost:3000").unwrap();
+    let _stream = TcpStream::connect("localhost:3000").unwrap();
 
     println!("fiunished wr");
-    loop {}
+    loop {
+        thread::sleep(Duration::from_secs(1));
+    }
+}
+
+// the validator uses the first key in ./keystore, creating one if it is empty.
+// The passphrase comes from KEYSTORE_PASSPHRASE
+fn validator_key() -> Result<ValidatorKey, String> {
+    let passphrase = env::var("KEYSTORE_PASSPHRASE")
+        .map_err(|_| "error: KEYSTORE_PASSPHRASE is not set".to_string())?;
+    let dir = "keystore".to_string();
+    let keystore = Keystore::open(&dir)?;
+    let address = match keystore.list()?.first() {
+        Some((address, _)) => *address,
+        None => keystore.generate(Scheme::Secp256k1, &passphrase)?,
+    };
+    Ok(ValidatorKey::Keystore {
+        dir,
+        address,
+        passphrase,
+    })
+}
+
+// each node keeps its chain and sync state under ./data/<id>
+fn data_dir(id: &str) -> String {
+    format!("data/{}", id)
+}
+
+fn exit_with(err: &str) -> ! {
+    eprintln!("{}", err);
+    process::exit(1)
 }
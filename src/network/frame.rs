@@ -0,0 +1,204 @@
+use std::fmt;
+use std::io::{self, ErrorKind, Read, Write};
+
+// Every message on a tcp stream is sent as one frame, so the reader knows where
+// it ends however the bytes arrive:
+//
+//   length u32 | message type u8 | flags u8 | checksum u32 if flagged | payload
+//
+// the length counts the payload only and the checksum is the crc32 of the type
+// and payload. Numbers are little endian
+pub const FRAME_HEADER_SIZE: usize = 6;
+pub const DEFAULT_MAX_FRAME_SIZE: usize = 16 * 1024 * 1024;
+
+const FLAG_CHECKSUM: u8 = 0x1;
+
+#[derive(Debug)]
+pub enum FrameError {
+    // the stream failed, the kind tells a reset from a timeout
+    Io(io::Error),
+    // the frame is malformed or too large, the stream can't be trusted after it
+    Invalid(String),
+}
+
+impl fmt::Display for FrameError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            FrameError::Io(e) => write!(f, "error: frame io: {}", e),
+            FrameError::Invalid(reason) => write!(f, "error: {}", reason),
+        }
+    }
+}
+
+fn invalid(reason: impl Into<String>) -> FrameError {
+    FrameError::Invalid(reason.into())
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct FrameCodec {
+    // the largest payload sent or accepted, larger frames are refused before
+    // anything is allocated for them
+    pub max_frame_size: usize,
+    // whether sent frames carry a checksum, received ones are checked either way
+    pub checksum: bool,
+}
+
+impl Default for FrameCodec {
+    fn default() -> Self {
+        FrameCodec::new(DEFAULT_MAX_FRAME_SIZE)
+    }
+}
+
+impl FrameCodec {
+    pub fn new(max_frame_size: usize) -> Self {
+        FrameCodec {
+            max_frame_size,
+            checksum: true,
+        }
+    }
+
+    pub fn write_frame(
+        &self,
+        writer: &mut impl Write,
+        message_type: u8,
+        payload: &[u8],
+    ) -> Result<(), FrameError> {
+        if payload.len() > self.max_frame_size {
+            return Err(invalid(format!(
+                "frame of {} bytes is over the limit of {}",
+                payload.len(),
+                self.max_frame_size
+            )));
+        }
+        let mut frame = Vec::with_capacity(FRAME_HEADER_SIZE + 4 + payload.len());
+        frame.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+        frame.push(message_type);
+        if self.checksum {
+            frame.push(FLAG_CHECKSUM);
+            frame.extend_from_slice(&checksum(message_type, payload).to_le_bytes());
+        } else {
+            frame.push(0);
+        }
+        frame.extend_from_slice(payload);
+        // one buffer per frame. write_all may still take several writes, so callers
+        // sharing a stream between threads must hold a lock around the call
+        writer.write_all(&frame).map_err(FrameError::Io)
+    }
+
+    // returns None when the stream ends cleanly between frames
+    pub fn read_frame(&self, reader: &mut impl Read) -> Result<Option<(u8, Vec<u8>)>, FrameError> {
+        let mut header = [0u8; FRAME_HEADER_SIZE];
+        match read_full(reader, &mut header)? {
+            0 => return Ok(None),
+            FRAME_HEADER_SIZE => {}
+            _ => return Err(invalid("truncated frame header")),
+        }
+        let len = u32::from_le_bytes(header[0..4].try_into().unwrap()) as usize;
+        let message_type = header[4];
+        let flags = header[5];
+        if len > self.max_frame_size {
+            return Err(invalid(format!(
+                "frame of {} bytes is over the limit of {}",
+                len, self.max_frame_size
+            )));
+        }
+        if flags & !FLAG_CHECKSUM != 0 {
+            return Err(invalid(format!("unknown frame flags {:#x}", flags)));
+        }
+
+        let mut expected = None;
+        if flags & FLAG_CHECKSUM != 0 {
+            let mut bytes = [0u8; 4];
+            if read_full(reader, &mut bytes)? != bytes.len() {
+                return Err(invalid("truncated frame header"));
+            }
+            expected = Some(u32::from_le_bytes(bytes));
+        }
+        let mut payload = vec![0u8; len];
+        if read_full(reader, &mut payload)? != len {
+            return Err(invalid(format!("truncated frame of {} bytes", len)));
+        }
+        if expected.is_some_and(|expected| expected != checksum(message_type, &payload)) {
+            return Err(invalid("frame checksum mismatch"));
+        }
+        Ok(Some((message_type, payload)))
+    }
+}
+
+fn checksum(message_type: u8, payload: &[u8]) -> u32 {
+    let mut hasher = crc32fast::Hasher::new();
+    hasher.update(&[message_type]);
+    hasher.update(payload);
+    hasher.finalize()
+}
+
+// like read_exact, but reports how much was read when the stream ends early
+fn read_full(reader: &mut impl Read, buf: &mut [u8]) -> Result<usize, FrameError> {
+    let mut n = 0;
+    while n < buf.len() {
+        match reader.read(&mut buf[n..]) {
+            Ok(0) => break,
+            Ok(read) => n += read,
+            Err(e) if e.kind() == ErrorKind::Interrupted => {}
+            Err(e) => return Err(FrameError::Io(e)),
+        }
+    }
+    Ok(n)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    #[test]
+    fn test_round_trip() {
+        let codec = FrameCodec::new(4096);
+        let unchecked = FrameCodec {
+            checksum: false,
+            ..codec
+        };
+        let big = vec![7u8; 3000];
+        let mut stream = vec![];
+        codec.write_frame(&mut stream, 1, b"first").unwrap();
+        unchecked.write_frame(&mut stream, 2, &big).unwrap();
+        codec.write_frame(&mut stream, 3, &[]).unwrap();
+
+        // frames come apart again however the bytes were joined
+        let mut reader = Cursor::new(stream);
+        assert_eq!(
+            codec.read_frame(&mut reader).unwrap(),
+            Some((1, b"first".to_vec()))
+        );
+        assert_eq!(codec.read_frame(&mut reader).unwrap(), Some((2, big)));
+        assert_eq!(codec.read_frame(&mut reader).unwrap(), Some((3, vec![])));
+        assert_eq!(codec.read_frame(&mut reader).unwrap(), None);
+    }
+
+    #[test]
+    fn test_invalid_frames() {
+        let codec = FrameCodec::new(16);
+        assert!(codec.write_frame(&mut vec![], 1, &[0; 17]).is_err());
+
+        // a peer announcing a huge frame is refused before reading it
+        let mut oversize = (u32::MAX).to_le_bytes().to_vec();
+        oversize.extend_from_slice(&[1, 0]);
+        assert!(codec.read_frame(&mut Cursor::new(oversize)).is_err());
+
+        let mut frame = vec![];
+        codec.write_frame(&mut frame, 1, b"payload").unwrap();
+        for len in 1..frame.len() {
+            assert!(codec.read_frame(&mut Cursor::new(&frame[..len])).is_err());
+        }
+
+        let mut corrupt = frame.clone();
+        *corrupt.last_mut().unwrap() ^= 1;
+        assert!(codec.read_frame(&mut Cursor::new(corrupt)).is_err());
+        let mut wrong_type = frame.clone();
+        wrong_type[4] = 2;
+        assert!(codec.read_frame(&mut Cursor::new(wrong_type)).is_err());
+        let mut unknown_flags = frame;
+        unknown_flags[5] |= 0x2;
+        assert!(codec.read_frame(&mut Cursor::new(unknown_flags)).is_err());
+    }
+}
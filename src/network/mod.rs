@@ -1,5 +1,9 @@
+pub mod frame;
 pub mod local_transport;
+pub mod orphanpool;
+pub mod rejection;
 pub mod rpc;
 pub mod server;
+pub mod sync;
 pub mod tcp_transport;
 pub mod txpool;
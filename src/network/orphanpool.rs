@@ -0,0 +1,184 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+use crate::{core::block::Block, types::hash::Hash};
+
+pub const DEFAULT_MAX_ORPHANS: usize = 100;
+pub const DEFAULT_MAX_ORPHAN_AGE: Duration = Duration::from_secs(10 * 60);
+
+struct Orphan {
+    block: Block,
+    from: SocketAddr,
+    received: Instant,
+}
+
+// OrphanPool holds blocks whose parent is not known yet, until the parent
+// arrives or the block gets too old. When full, the oldest orphan is evicted.
+pub struct OrphanPool {
+    orphans: HashMap<Hash, Orphan>,
+    // parent hash => hashes of the orphans waiting for it
+    children: HashMap<Hash, Vec<Hash>>,
+    max_count: usize,
+    max_age: Duration,
+}
+
+impl OrphanPool {
+    pub fn new(max_count: usize, max_age: Duration) -> Self {
+        OrphanPool {
+            orphans: HashMap::new(),
+            children: HashMap::new(),
+            max_count,
+            max_age,
+        }
+    }
+
+    // returns false if the block was already held
+    pub fn add(&mut self, mut block: Block, from: SocketAddr) -> bool {
+        self.expire();
+        let hash = block.hash();
+        if self.orphans.contains_key(&hash) {
+            return false;
+        }
+
+        if self.orphans.len() >= self.max_count {
+            let oldest = self
+                .orphans
+                .iter()
+                .min_by_key(|(_, orphan)| orphan.received)
+                .map(|(hash, _)| *hash);
+            if let Some(oldest) = oldest {
+                self.remove(&oldest);
+            }
+        }
+
+        self.children
+            .entry(block.header.prev_block_hash)
+            .or_default()
+            .push(hash);
+        self.orphans.insert(
+            hash,
+            Orphan {
+                block,
+                from,
+                received: Instant::now(),
+            },
+        );
+        true
+    }
+
+    pub fn has(&self, hash: &Hash) -> bool {
+        self.orphans.contains_key(hash)
+    }
+
+    pub fn len(&self) -> usize {
+        self.orphans.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.orphans.is_empty()
+    }
+
+    // removes and returns the orphans waiting for the given parent, with the peer that sent each
+    pub fn take_children(&mut self, parent: &Hash) -> Vec<(Block, SocketAddr)> {
+        let hashes = self.children.remove(parent).unwrap_or_default();
+        hashes
+            .iter()
+            .filter_map(|hash| self.orphans.remove(hash))
+            .map(|orphan| (orphan.block, orphan.from))
+            .collect()
+    }
+
+    // follows parents through the pool from the given orphan to the first
+    // ancestor that is not held, which is the block that has to be fetched
+    pub fn missing_ancestor(&self, hash: &Hash) -> Hash {
+        let mut hash = *hash;
+        while let Some(orphan) = self.orphans.get(&hash) {
+            hash = orphan.block.header.prev_block_hash;
+        }
+        hash
+    }
+
+    pub fn expire(&mut self) {
+        let expired: Vec<Hash> = self
+            .orphans
+            .iter()
+            .filter(|(_, orphan)| orphan.received.elapsed() > self.max_age)
+            .map(|(hash, _)| *hash)
+            .collect();
+        for hash in expired {
+            self.remove(&hash);
+        }
+    }
+
+    fn remove(&mut self, hash: &Hash) {
+        if let Some(orphan) = self.orphans.remove(hash) {
+            let parent = &orphan.block.header.prev_block_hash;
+            if let Some(children) = self.children.get_mut(parent) {
+                children.retain(|child| child != hash);
+                if children.is_empty() {
+                    self.children.remove(parent);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::block::random_block;
+
+    fn from() -> SocketAddr {
+        "127.0.0.1:3000".parse().unwrap()
+    }
+
+    #[test]
+    fn test_take_children() {
+        let mut pool = OrphanPool::new(DEFAULT_MAX_ORPHANS, DEFAULT_MAX_ORPHAN_AGE);
+        let mut b1 = random_block(1, Hash::digest(b"parent"));
+        let b1_hash = b1.hash();
+        let b2 = random_block(2, b1_hash);
+
+        assert!(pool.add(b2, from()));
+        assert!(pool.add(b1.clone(), from()));
+        assert!(!pool.add(b1, from()));
+        assert_eq!(pool.len(), 2);
+
+        // the block to fetch is the parent of the oldest ancestor in the pool
+        let mut b2 = random_block(2, b1_hash);
+        assert_eq!(pool.missing_ancestor(&b2.hash()), Hash::digest(b"parent"));
+
+        let children = pool.take_children(&Hash::digest(b"parent"));
+        assert_eq!(children.len(), 1);
+        assert!(!pool.has(&b1_hash));
+        let children = pool.take_children(&b1_hash);
+        assert_eq!(children.len(), 1);
+        assert_eq!(pool.len(), 0);
+    }
+
+    #[test]
+    fn test_bounds() {
+        let mut pool = OrphanPool::new(2, DEFAULT_MAX_ORPHAN_AGE);
+        let mut b1 = random_block(1, Hash::digest(b"a"));
+        let mut b2 = random_block(1, Hash::digest(b"b"));
+        let mut b3 = random_block(1, Hash::digest(b"c"));
+        pool.add(b1.clone(), from());
+        std::thread::sleep(Duration::from_millis(5));
+        pool.add(b2.clone(), from());
+        pool.add(b3.clone(), from());
+
+        // the oldest orphan is evicted to make room
+        assert_eq!(pool.len(), 2);
+        assert!(!pool.has(&b1.hash()));
+        assert!(pool.has(&b2.hash()));
+        assert!(pool.has(&b3.hash()));
+        assert!(pool.take_children(&Hash::digest(b"a")).is_empty());
+
+        let mut pool = OrphanPool::new(2, Duration::from_millis(1));
+        pool.add(b1, from());
+        std::thread::sleep(Duration::from_millis(5));
+        pool.expire();
+        assert_eq!(pool.len(), 0);
+    }
+}
@@ -0,0 +1,43 @@
+use std::fmt;
+
+use crate::core::state::StateError;
+
+// why a block or transaction received from a peer was not accepted. Nothing
+// rejected is relayed
+#[derive(Debug, PartialEq)]
+pub enum Rejection {
+    // already in the chain, the mempool or the orphan pool
+    Duplicate,
+    WrongChain { expected: u32, got: u32 },
+    InvalidSignature(String),
+    // the sender's nonce doesn't allow the transaction yet, or any more
+    State(StateError),
+    // refused by Blockchain::add_block
+    InvalidBlock(String),
+    // the mempool is full of transactions paying more per byte
+    Underpriced,
+    // the sender already has a pooled transaction with the nonce, and the new
+    // one doesn't pay enough more to replace it
+    NonceInUse(u64),
+    // the sender has as many transactions pooled as allowed
+    SenderLimit(usize),
+}
+
+impl fmt::Display for Rejection {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Rejection::Duplicate => write!(f, "already known"),
+            Rejection::WrongChain { expected, got } => {
+                write!(f, "for chain {} => expected chain {}", got, expected)
+            }
+            Rejection::InvalidSignature(err) => write!(f, "invalid signature: {}", err),
+            Rejection::State(err) => write!(f, "{}", err),
+            Rejection::InvalidBlock(err) => write!(f, "invalid block: {}", err),
+            Rejection::Underpriced => write!(f, "fee too low for the full mempool"),
+            Rejection::NonceInUse(nonce) => write!(f, "nonce {} already pooled", nonce),
+            Rejection::SenderLimit(limit) => {
+                write!(f, "sender has {} transactions pooled already", limit)
+            }
+        }
+    }
+}
@@ -1,5 +1,8 @@
-use crate::core::{block::Block, transaction::Transaction};
-use std::{fmt::Error, net::SocketAddr};
+use crate::core::block::{Block, Header};
+use crate::core::transaction::Transaction;
+use crate::types::hash::Hash;
+use serde::{Deserialize, Serialize};
+use std::net::SocketAddr;
 
 pub type RPCDecodeFunc = fn(rpc: RPC) -> Result<DecodedMessage, String>;
 
@@ -7,11 +10,74 @@ type MessageType = u8;
 
 pub const MESSAGE_TYPE_TX: MessageType = 0x1;
 pub const MESSAGE_TYPE_BLOCK: MessageType = 0x2;
-// const MessageTypeGetBlocks: MessageType = 0x3;
-// const MessageTypeStatus: MessageType = 0x4;
-// const MessageTypeGetStatus: MessageType = 0x5;
-// const MessageTypeBlocks: MessageType = 0x6;
+// asks for a range of main chain blocks by height, answered with MESSAGE_TYPE_BLOCKS
+pub const MESSAGE_TYPE_GET_BLOCKS: MessageType = 0x3;
+// the first message on every connection, and the answer to MESSAGE_TYPE_GET_STATUS
+pub const MESSAGE_TYPE_STATUS: MessageType = 0x4;
+pub const MESSAGE_TYPE_GET_STATUS: MessageType = 0x5;
+pub const MESSAGE_TYPE_BLOCKS: MessageType = 0x6;
+// asks a peer for a single block by hash, answered with MESSAGE_TYPE_BLOCK
+pub const MESSAGE_TYPE_GET_BLOCK: MessageType = 0x7;
+// asks for the main chain headers after a block locator, answered with MESSAGE_TYPE_HEADERS
+pub const MESSAGE_TYPE_GET_HEADERS: MessageType = 0x8;
+pub const MESSAGE_TYPE_HEADERS: MessageType = 0x9;
 
+// peers speaking another version are refused during the handshake
+pub const PROTOCOL_VERSION: u32 = 1;
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct Status {
+    pub version: u32,
+    pub chain_id: u32,
+    pub genesis_hash: Hash,
+    pub height: u32,
+    // random per run, so a node can spot a connection to itself or a peer it
+    // is already connected to under another address
+    pub node_id: u64,
+}
+
+impl Status {
+    // whether a node with this status can be a peer of one with ours
+    pub fn check_compatible(&self, ours: &Status) -> Result<(), String> {
+        if self.version != ours.version {
+            return Err(format!(
+                "error: peer speaks protocol version {} => expected {}",
+                self.version, ours.version
+            ));
+        }
+        if self.chain_id != ours.chain_id {
+            return Err(format!(
+                "error: peer is on chain {} => expected {}",
+                self.chain_id, ours.chain_id
+            ));
+        }
+        if self.genesis_hash != ours.genesis_hash {
+            return Err(format!(
+                "error: peer has genesis block {} => expected {}",
+                self.genesis_hash, ours.genesis_hash
+            ));
+        }
+        if self.node_id == ours.node_id {
+            return Err("error: connected to self".to_string());
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct GetHeaders {
+    // see Blockchain::block_locator
+    pub locator: Vec<Hash>,
+    pub count: u32,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct GetBlocks {
+    pub start: u32,
+    pub count: u32,
+}
+
+#[allow(clippy::upper_case_acronyms)]
 #[derive(Debug)]
 pub struct RPC {
     pub from: SocketAddr,
@@ -21,9 +87,17 @@ pub struct RPC {
 pub enum Decoded {
     Transaction(Transaction),
     Block(Block),
+    GetBlock(Hash),
+    Status(Status),
+    GetStatus,
+    GetHeaders(GetHeaders),
+    Headers(Vec<Header>),
+    GetBlocks(GetBlocks),
+    Blocks(Vec<Block>),
 }
 
-// use Message to format message to send bytes
+// a message to send to a peer, framed with its type by TcpPeer::send. The
+// receiver gets it back as an RPC whose data starts with the type
 pub struct Message {
     header: u8,
     data: Vec<u8>,
@@ -31,17 +105,15 @@ pub struct Message {
 
 impl Message {
     pub fn new(header: u8, data: Vec<u8>) -> Self {
-        let mut message = Message { header, data };
-        message.format();
-        message
+        Message { header, data }
     }
 
-    pub fn format(&mut self) {
-        self.data.insert(0, self.header);
+    pub fn header(&self) -> u8 {
+        self.header
     }
 
-    pub fn bytes(self) -> Vec<u8> {
-        return self.data;
+    pub fn data(&self) -> &[u8] {
+        &self.data
     }
 }
 
@@ -51,37 +123,136 @@ pub struct DecodedMessage {
 }
 
 pub fn default_rpc_decode(rpc: RPC) -> Result<DecodedMessage, String> {
-    if rpc.data.len() < 1 {
+    if rpc.data.is_empty() {
         return Err(String::from("RPC data is empty"));
     }
-    let message_type = *rpc.data.get(0).unwrap();
+    let message_type = rpc.data[0];
     match message_type {
         MESSAGE_TYPE_TX => {
             let tx_data = &rpc.data[1..];
 
             if let Ok(tx_decode) = serde_json::from_slice::<Transaction>(tx_data) {
-                return Ok(DecodedMessage {
+                Ok(DecodedMessage {
                     from: rpc.from,
                     data: Decoded::Transaction(tx_decode),
-                });
+                })
             } else {
-                return Err(String::from("could not parse transaction RPC"));
+                Err(String::from("could not parse transaction RPC"))
             }
         }
         MESSAGE_TYPE_BLOCK => {
             let block_data = &rpc.data[1..];
 
             if let Ok(block_decode) = serde_json::from_slice::<Block>(block_data) {
-                return Ok(DecodedMessage {
+                Ok(DecodedMessage {
                     from: rpc.from,
                     data: Decoded::Block(block_decode),
-                });
+                })
             } else {
-                return Err(String::from("could not parse block RPC"));
+                Err(String::from("could not parse block RPC"))
             }
         }
-        _ => {
-            return Err(format!("invalid message header {}", message_type));
+        MESSAGE_TYPE_GET_BLOCK => match Hash::from_slice(&rpc.data[1..]) {
+            Ok(hash) => Ok(DecodedMessage {
+                from: rpc.from,
+                data: Decoded::GetBlock(hash),
+            }),
+            Err(_) => Err(String::from("could not parse get block RPC")),
+        },
+        MESSAGE_TYPE_STATUS => match serde_json::from_slice::<Status>(&rpc.data[1..]) {
+            Ok(status) => Ok(DecodedMessage {
+                from: rpc.from,
+                data: Decoded::Status(status),
+            }),
+            Err(_) => Err(String::from("could not parse status RPC")),
+        },
+        MESSAGE_TYPE_GET_STATUS => Ok(DecodedMessage {
+            from: rpc.from,
+            data: Decoded::GetStatus,
+        }),
+        MESSAGE_TYPE_GET_HEADERS => match serde_json::from_slice(&rpc.data[1..]) {
+            Ok(request) => Ok(DecodedMessage {
+                from: rpc.from,
+                data: Decoded::GetHeaders(request),
+            }),
+            Err(_) => Err(String::from("could not parse get headers RPC")),
+        },
+        MESSAGE_TYPE_HEADERS => match serde_json::from_slice(&rpc.data[1..]) {
+            Ok(headers) => Ok(DecodedMessage {
+                from: rpc.from,
+                data: Decoded::Headers(headers),
+            }),
+            Err(_) => Err(String::from("could not parse headers RPC")),
+        },
+        MESSAGE_TYPE_GET_BLOCKS => match serde_json::from_slice(&rpc.data[1..]) {
+            Ok(request) => Ok(DecodedMessage {
+                from: rpc.from,
+                data: Decoded::GetBlocks(request),
+            }),
+            Err(_) => Err(String::from("could not parse get blocks RPC")),
+        },
+        MESSAGE_TYPE_BLOCKS => match serde_json::from_slice(&rpc.data[1..]) {
+            Ok(blocks) => Ok(DecodedMessage {
+                from: rpc.from,
+                data: Decoded::Blocks(blocks),
+            }),
+            Err(_) => Err(String::from("could not parse blocks RPC")),
+        },
+        _ => Err(format!("invalid message header {}", message_type)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn status() -> Status {
+        Status {
+            version: PROTOCOL_VERSION,
+            chain_id: 1,
+            genesis_hash: Hash::digest(b"genesis"),
+            height: 10,
+            node_id: 1,
+        }
+    }
+
+    #[test]
+    fn test_status_handshake() {
+        let ours = status();
+        let peer = Status {
+            height: 3,
+            node_id: 2,
+            ..status()
+        };
+        assert!(peer.check_compatible(&ours).is_ok());
+
+        assert!(ours.check_compatible(&ours).is_err());
+        for incompatible in [
+            Status {
+                version: PROTOCOL_VERSION + 1,
+                ..peer.clone()
+            },
+            Status {
+                chain_id: 2,
+                ..peer.clone()
+            },
+            Status {
+                genesis_hash: Hash::zero(),
+                ..peer.clone()
+            },
+        ] {
+            assert!(incompatible.check_compatible(&ours).is_err());
+        }
+
+        let mut data = vec![MESSAGE_TYPE_STATUS];
+        data.extend(serde_json::to_vec(&peer).unwrap());
+        let rpc = RPC {
+            from: "127.0.0.1:3000".parse().unwrap(),
+            data,
+        };
+        match default_rpc_decode(rpc).unwrap().data {
+            Decoded::Status(decoded) => assert_eq!(decoded, peer),
+            _ => panic!("expected a status"),
         }
     }
 }
@@ -1,34 +1,112 @@
 use std::collections::HashMap;
-use std::io::Write;
-use std::net::{SocketAddr, TcpStream};
+use std::net::{Shutdown, SocketAddr, TcpStream};
+use std::path::Path;
 use std::sync::mpsc::{channel, Receiver, Sender};
-use std::sync::{Arc, Mutex, RwLock, RwLockWriteGuard};
+use std::sync::{Arc, RwLock, RwLockWriteGuard};
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
-use super::rpc::{default_rpc_decode, RPCDecodeFunc, RPC};
+use super::frame::{FrameCodec, DEFAULT_MAX_FRAME_SIZE};
+use super::orphanpool::{OrphanPool, DEFAULT_MAX_ORPHANS, DEFAULT_MAX_ORPHAN_AGE};
+use super::rejection::Rejection;
+use super::rpc::{default_rpc_decode, GetBlocks, GetHeaders, RPCDecodeFunc, RPC};
+use super::sync::{SyncRequest, Syncer, MAX_BLOCKS_PER_REQUEST, MAX_HEADERS_PER_REQUEST};
 use super::tcp_transport::TCPTransport;
-use super::txpool::{self, TxPool};
-use crate::core::block::{new_block_from_prev_header, Block, Header};
+use super::txpool::{TxPool, DEFAULT_MAX_PER_SENDER, DEFAULT_MAX_POOL_SIZE};
+use crate::core::assembler::{select_transactions, BlockLimits};
+use crate::core::block::{calculate_data_hash, new_block_from_prev_header, Block, Header};
 use crate::core::blockchain::Blockchain;
-use crate::crypto::keypair::KeyPair;
-use crate::network::rpc::{Decoded, Message, MESSAGE_TYPE_BLOCK};
-use crate::network::tcp_transport::TcpPeer;
+use crate::core::state::State;
+use crate::core::storage::{FileBlockStore, Recovery};
+use crate::core::transaction::Transaction;
+use crate::crypto::keypair::{random_bytes, KeyPair};
+use crate::crypto::keystore::Keystore;
+use crate::crypto::scheme::Signer;
+use crate::network::frame::FrameError;
+use crate::network::rpc::{
+    Decoded, Message, Status, MESSAGE_TYPE_BLOCK, MESSAGE_TYPE_BLOCKS, MESSAGE_TYPE_GET_BLOCK,
+    MESSAGE_TYPE_GET_BLOCKS, MESSAGE_TYPE_GET_HEADERS, MESSAGE_TYPE_HEADERS, MESSAGE_TYPE_STATUS,
+    MESSAGE_TYPE_TX, PROTOCOL_VERSION,
+};
+use crate::network::tcp_transport::{PeerEvent, TcpPeer};
+use crate::types::address::{encode_address, Address};
+use crate::types::hash::Hash;
 
 pub struct ServerOpts {
     pub listen_addr: String,
+    pub chain_id: u32,
+    // blocks are kept in memory only when no data directory is given
+    pub data_dir: Option<String>,
     pub seed_nodes: Vec<String>,
-    pub key_pair: Option<KeyPair>,
+    // the node produces blocks when given a validator key
+    pub validator_key: Option<ValidatorKey>,
     pub block_time: u32,
     pub rpc_decode_func: Option<RPCDecodeFunc>,
+    // the largest message accepted from or sent to peers, DEFAULT_MAX_FRAME_SIZE if not set
+    pub max_frame_size: Option<usize>,
+    // how many transactions produced blocks may hold, BlockLimits::default() if not set
+    pub block_limits: Option<BlockLimits>,
+    // how many transactions the mempool holds, DEFAULT_MAX_POOL_SIZE if not set
+    pub max_pool_size: Option<usize>,
+    // how many of them may come from one sender, DEFAULT_MAX_PER_SENDER if not set
+    pub max_pool_per_sender: Option<usize>,
+    // balances credited at genesis, every node on the chain must use the same ones
+    pub genesis_allocations: Vec<(Address, u64)>,
+    // the keys allowed to sign blocks, also the same on every node
+    pub validators: Vec<Address>,
 }
+
+pub enum ValidatorKey {
+    // a key from a fixed seed, for tests and local networks
+    Seed(u64),
+    Keystore {
+        dir: String,
+        address: Address,
+        passphrase: String,
+    },
+}
+
+impl ValidatorKey {
+    pub fn address(&self) -> Address {
+        match self {
+            ValidatorKey::Seed(seed) => KeyPair::new(*seed).address(),
+            ValidatorKey::Keystore { address, .. } => *address,
+        }
+    }
+
+    // consumes the key so the passphrase is dropped once the signer is loaded
+    pub fn load(self) -> Result<Box<dyn Signer>, String> {
+        match self {
+            ValidatorKey::Seed(seed) => Ok(Box::new(KeyPair::new(seed))),
+            ValidatorKey::Keystore {
+                dir,
+                address,
+                passphrase,
+            } => Keystore::open(&dir)?.load(&address, &passphrase),
+        }
+    }
+}
+
+// how long a new connection has to send its status before it is dropped
+pub const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
 pub struct Server {
     pub opts: ServerOpts,
 
     pub tcp_transport: TCPTransport,
+    // peers that completed the handshake
     pub peer_map: Arc<RwLock<HashMap<SocketAddr, TcpPeer>>>,
+    // connections that have not sent a compatible status yet, with when they connected
+    pub pending_peers: HashMap<SocketAddr, (TcpPeer, Instant)>,
+    // the last status each peer in peer_map sent
+    pub peer_status: HashMap<SocketAddr, Status>,
+    pub node_id: u64,
+    pub genesis_hash: Hash,
     pub peer_sender: Sender<TcpPeer>,
     pub peer_receiver: Receiver<TcpPeer>,
+    // peers report here when their connection ends
+    pub event_sender: Sender<PeerEvent>,
+    pub event_receiver: Receiver<PeerEvent>,
 
     pub rpc_decode_func: RPCDecodeFunc,
     pub rpc_sender: Sender<RPC>,
@@ -36,81 +114,531 @@ pub struct Server {
 
     pub chain: Arc<RwLock<Blockchain>>,
     pub mempool: Arc<RwLock<TxPool>>,
+    // received blocks whose parent is not known yet
+    pub orphans: OrphanPool,
+    // catches up with peers that are ahead
+    pub sync: Syncer,
 
-    pub is_validator: bool,
+    // signs the blocks this node produces
+    pub validator: Option<Arc<dyn Signer>>,
 
     pub quit_sender: Sender<()>,
     pub quit_receiver: Receiver<()>,
 }
 
 impl Server {
-    pub fn new(opts: ServerOpts) -> Self {
+    pub fn new(mut opts: ServerOpts) -> Result<Self, String> {
         let (quit_sender, quit_receiver) = channel();
         let (peer_sender, peer_receiver) = channel();
+        let (event_sender, event_receiver) = channel();
         let (rpc_sender, rpc_receiver) = channel();
+        let codec = FrameCodec::new(opts.max_frame_size.unwrap_or(DEFAULT_MAX_FRAME_SIZE));
+        let validator = match opts.validator_key.take() {
+            Some(key) if !opts.validators.contains(&key.address()) => {
+                return Err(format!(
+                    "validator key {} is not in the validator set",
+                    encode_address(&key.address())
+                ))
+            }
+            Some(key) => Some(Arc::from(key.load()?)),
+            None => None,
+        };
+        let chain = new_blockchain(&opts)?;
+        let genesis_hash = chain.get_header(0).unwrap().hash();
+        let headers_path = opts
+            .data_dir
+            .as_ref()
+            .map(|dir| Path::new(dir).join("sync-headers.jsonl"));
+        let sync = Syncer::new(headers_path, &chain);
 
-        Server {
-            tcp_transport: TCPTransport::new(opts.listen_addr.clone(), peer_sender.clone()),
+        Ok(Server {
+            tcp_transport: TCPTransport::new(opts.listen_addr.clone(), peer_sender.clone(), codec),
             peer_map: Arc::new(RwLock::new(HashMap::new())),
-            peer_sender: peer_sender,
-            peer_receiver: peer_receiver,
+            pending_peers: HashMap::new(),
+            peer_status: HashMap::new(),
+            node_id: u64::from_le_bytes(random_bytes()?),
+            genesis_hash,
+            peer_sender,
+            peer_receiver,
+            event_sender,
+            event_receiver,
 
-            rpc_sender: rpc_sender,
-            rpc_receiver: rpc_receiver,
+            rpc_sender,
+            rpc_receiver,
 
-            chain: Arc::new(RwLock::new(Blockchain::new(genesis_block()))),
-            mempool: Arc::new(RwLock::new(TxPool::new())),
+            chain: Arc::new(RwLock::new(chain)),
+            mempool: Arc::new(RwLock::new(TxPool::new(
+                opts.max_pool_size.unwrap_or(DEFAULT_MAX_POOL_SIZE),
+                opts.max_pool_per_sender.unwrap_or(DEFAULT_MAX_PER_SENDER),
+            ))),
+            orphans: OrphanPool::new(DEFAULT_MAX_ORPHANS, DEFAULT_MAX_ORPHAN_AGE),
+            sync,
 
-            is_validator: opts.key_pair.is_some(),
+            validator,
             rpc_decode_func: opts.rpc_decode_func.unwrap_or(default_rpc_decode),
             opts,
 
             quit_sender,
             quit_receiver,
-        }
+        })
     }
 
-    pub fn start(&mut self) {
+    // runs until told to quit, fails if the node can't listen for peers
+    pub fn start(&mut self) -> Result<(), String> {
         let tr = &self.tcp_transport;
-        tr.start();
+        tr.start()?;
         thread::sleep(Duration::from_secs(1));
         self.bootstrap_network();
-        if self.is_validator {
-            self.validator_loop();
+        if let Some(signer) = self.validator.clone() {
+            self.validator_loop(signer);
         }
         loop {
-            if let Ok(_) = self.quit_receiver.try_recv() {
+            if self.quit_receiver.try_recv().is_ok() {
                 break;
             }
             if let Ok(tcp_peer) = self.peer_receiver.try_recv() {
-                let addr = tcp_peer.stream.peer_addr().unwrap();
-                println!("received peer {}", addr);
-                let rpc_sender = self.rpc_sender.clone();
-                let mut tcp_peer_clone = tcp_peer.clone();
-                thread::spawn(move || tcp_peer_clone.read_loop(rpc_sender));
-
-                self.peer_map.write().unwrap().insert(addr, tcp_peer);
+                self.add_peer(tcp_peer);
+            }
+            if let Ok(event) = self.event_receiver.try_recv() {
+                self.handle_peer_event(event);
             }
+            self.expire_handshakes();
+            self.poll_sync();
             if let Ok(rpc) = self.rpc_receiver.try_recv() {
-                println!("{:?}", rpc);
-                let decoded_message = (self.rpc_decode_func)(rpc);
-
-                match decoded_message {
-                    Ok(message) => match message.data {
-                        Decoded::Block(block) => {
-                            println!("{:?}", block);
-                        }
-                        Decoded::Transaction(transaction) => {
-                            println!("{:?}", transaction);
-                        }
-                    },
-                    Err(err) => {
-                        println!("{}", err);
+                self.handle_rpc(rpc);
+            }
+        }
+        Ok(())
+    }
+
+    fn handle_rpc(&mut self, rpc: RPC) {
+        // a disconnected peer's reader may still have delivered messages
+        if !self.is_connected(&rpc.from) {
+            return;
+        }
+        let decoded_message = (self.rpc_decode_func)(rpc);
+
+        match decoded_message {
+            // nothing but the handshake is accepted before it completes
+            Ok(message)
+                if self.pending_peers.contains_key(&message.from)
+                    && !matches!(message.data, Decoded::Status(_) | Decoded::GetStatus) =>
+            {
+                println!("ignoring message from {} before its status", message.from);
+            }
+            Ok(message) => match message.data {
+                Decoded::Block(block) => {
+                    if let Err(rejection) = self.process_block(message.from, block) {
+                        println!("rejected block from {}: {}", message.from, rejection);
+                    }
+                }
+                Decoded::Transaction(transaction) => {
+                    if let Err(rejection) =
+                        self.process_transaction(Some(message.from), transaction)
+                    {
+                        println!("rejected transaction from {}: {}", message.from, rejection);
                     }
                 }
-                // let mut b_decode: Block = serde_json::from_slice(&rpc).unwrap();
-                // println!("{:?}", b_decode);
+                Decoded::GetBlock(hash) => {
+                    let block = self.chain.read().unwrap().get_known_block(&hash);
+                    match block {
+                        Ok(block) => self.send_to_peer(
+                            &message.from,
+                            Message::new(MESSAGE_TYPE_BLOCK, block.encode().into_bytes()),
+                        ),
+                        Err(err) => println!("{}", err),
+                    }
+                }
+                Decoded::Status(status) => self.handle_status(message.from, status),
+                Decoded::GetStatus => self.send_to_peer(&message.from, self.status_message()),
+                Decoded::GetHeaders(request) => self.send_headers(message.from, request),
+                Decoded::Headers(headers) => {
+                    let chain = self.chain.read().unwrap();
+                    let result = self.sync.on_headers(message.from, headers, &chain);
+                    drop(chain);
+                    if let Err(err) = result {
+                        self.drop_sync_peer(&message.from, &err);
+                    }
+                }
+                Decoded::GetBlocks(request) => self.send_blocks(message.from, request),
+                Decoded::Blocks(blocks) => {
+                    if let Err(err) = self.sync.on_blocks(message.from, blocks) {
+                        self.drop_sync_peer(&message.from, &err);
+                    }
+                    self.add_synced_blocks();
+                }
+            },
+            Err(err) => {
+                println!("{}", err);
+            }
+        }
+    }
+
+    // admits a transaction from a peer, or from this node when from is none, to
+    // the mempool and relays it to every other peer. Anything already known is
+    // rejected, so each transaction is relayed once
+    pub fn process_transaction(
+        &mut self,
+        from: Option<SocketAddr>,
+        mut tx: Transaction,
+    ) -> Result<(), Rejection> {
+        let chain = self.chain.read().unwrap();
+        let mut mempool = self.mempool.write().unwrap();
+        if mempool.has(&mut tx) || chain.get_transaction(&tx.hash()).is_ok() {
+            return Err(Rejection::Duplicate);
+        }
+        if tx.data.chain_id != chain.chain_id() {
+            return Err(Rejection::WrongChain {
+                expected: chain.chain_id(),
+                got: tx.data.chain_id,
+            });
+        }
+        // checked through the cache so the block including it doesn't check again
+        chain
+            .sig_cache()
+            .verify(&tx, chain.chain_id())
+            .map_err(Rejection::InvalidSignature)?;
+        mempool.add(tx.clone(), chain.state())?;
+        drop(mempool);
+        drop(chain);
+
+        println!("added transaction {}", tx.hash());
+        broadcast(
+            &self.peer_map,
+            &self.event_sender,
+            MESSAGE_TYPE_TX,
+            tx.encode().as_bytes(),
+            from,
+        );
+        Ok(())
+    }
+
+    // blocks with an unknown parent are held as orphans and their missing
+    // ancestor is requested from the sender, unless sync is already fetching
+    // it; once a block is added, any orphans waiting for it are added as well.
+    // Added blocks are relayed to every peer but the one they came from
+    fn process_block(&mut self, from: SocketAddr, mut block: Block) -> Result<(), Rejection> {
+        let chain = self.chain.read().unwrap();
+        let hash = block.hash();
+        if chain.is_known(&hash) {
+            return Err(Rejection::Duplicate);
+        }
+        if block.header.chain_id != chain.chain_id() {
+            return Err(Rejection::WrongChain {
+                expected: chain.chain_id(),
+                got: block.header.chain_id,
+            });
+        }
+        if !chain.is_known(&block.header.prev_block_hash) {
+            if !self.orphans.add(block, from) {
+                return Err(Rejection::Duplicate);
+            }
+            if !self.sync.is_syncing() {
+                let missing = self.orphans.missing_ancestor(&hash);
+                println!(
+                    "holding orphan block {} => fetching {} from {}",
+                    hash, missing, from
+                );
+                self.send_to_peer(
+                    &from,
+                    Message::new(MESSAGE_TYPE_GET_BLOCK, missing.as_bytes().to_vec()),
+                );
+            }
+            return Ok(());
+        }
+        drop(chain);
+        self.connect_blocks(vec![(block, Some(from))], true)
+    }
+
+    // adds blocks whose parent is known, each with the peer it came from and
+    // followed by any orphans waiting for it. Fails with the first of the given
+    // blocks to be refused, refused orphans are only logged. Accepted blocks
+    // are relayed once the chain lock is released
+    fn connect_blocks(
+        &mut self,
+        blocks: Vec<(Block, Option<SocketAddr>)>,
+        relay: bool,
+    ) -> Result<(), Rejection> {
+        let mut chain = self.chain.write().unwrap();
+        let mut result = Ok(());
+        // encoded block, the peer it came from and its height
+        let mut accepted: Vec<(String, Option<SocketAddr>, u32)> = vec![];
+        // given blocks are added in order, before the orphans they release
+        let mut queue: Vec<(Block, Option<SocketAddr>, bool)> = blocks
+            .into_iter()
+            .rev()
+            .map(|(block, from)| (block, from, true))
+            .collect();
+        while let Some((mut block, from, given)) = queue.pop() {
+            let hash = block.hash();
+            // a released orphan may also be among the given blocks
+            if chain.is_known(&hash) {
+                continue;
+            }
+            let encoded = block.encode();
+            let height = block.header.height;
+            let hashes = transaction_hashes(&mut block);
+            match chain.add_block(block) {
+                Ok(()) => {
+                    println!("added block {}", hash);
+                    remove_included(&chain, &self.mempool, &hashes);
+                    accepted.push((encoded, from, height));
+                    let children = self.orphans.take_children(&hash);
+                    queue.extend(
+                        children
+                            .into_iter()
+                            .map(|(block, from)| (block, Some(from), false)),
+                    );
+                }
+                Err(err) if given && result.is_ok() => result = Err(Rejection::InvalidBlock(err)),
+                Err(err) => println!("rejected orphan block {}: {}", hash, err),
+            }
+        }
+        return_orphaned_transactions(&mut chain, &self.mempool);
+        drop(chain);
+
+        for (encoded, from, height) in accepted {
+            // a peer is only known to be this high once its block checked out
+            if let Some(from) = from {
+                self.sync.saw_block(from, height);
+            }
+            if relay {
+                let data = encoded.as_bytes();
+                broadcast(
+                    &self.peer_map,
+                    &self.event_sender,
+                    MESSAGE_TYPE_BLOCK,
+                    data,
+                    from,
+                );
+            }
+        }
+        result
+    }
+
+    fn poll_sync(&mut self) {
+        let requests = {
+            let chain = self.chain.read().unwrap();
+            self.sync.poll(&chain, Instant::now())
+        };
+        for (peer, request) in requests {
+            let message = match request {
+                SyncRequest::Headers(request) => Message::new(
+                    MESSAGE_TYPE_GET_HEADERS,
+                    serde_json::to_vec(&request).unwrap(),
+                ),
+                SyncRequest::Blocks(request) => Message::new(
+                    MESSAGE_TYPE_GET_BLOCKS,
+                    serde_json::to_vec(&request).unwrap(),
+                ),
+            };
+            self.send_to_peer(&peer, message);
+        }
+    }
+
+    fn add_synced_blocks(&mut self) {
+        let ready = {
+            let chain = self.chain.read().unwrap();
+            self.sync.take_ready(&chain)
+        };
+        if ready.is_empty() {
+            return;
+        }
+        let mut sent: Vec<(Hash, u32, SocketAddr)> = vec![];
+        let ready = ready
+            .into_iter()
+            .map(|(mut block, from)| {
+                sent.push((block.hash(), block.header.height, from));
+                (block, Some(from))
+            })
+            .collect();
+        if let Err(rejection) = self.connect_blocks(ready, false) {
+            println!(
+                "sync: downloaded block rejected => starting over: {}",
+                rejection
+            );
+            // the blocks before the refused one were added
+            let refused = {
+                let chain = self.chain.read().unwrap();
+                sent.into_iter().find(|(hash, _, _)| !chain.is_known(hash))
+            };
+            match refused {
+                Some((_, height, from)) => {
+                    for peer in self.sync.block_rejected(height, from) {
+                        println!("sync: disconnecting {} for sending an invalid chain", peer);
+                        self.remove_peer(&peer);
+                    }
+                }
+                None => self.sync.reset(),
+            }
+        } else if !self.sync.is_syncing() {
+            println!(
+                "sync: caught up at height {}",
+                self.chain.read().unwrap().height()
+            );
+        }
+    }
+
+    // a peer that answers sync requests with data that doesn't check out is not asked again
+    fn drop_sync_peer(&mut self, addr: &SocketAddr, err: &str) {
+        println!("sync: {} => disconnecting {}", err, addr);
+        self.remove_peer(addr);
+    }
+
+    fn send_headers(&self, to: SocketAddr, request: GetHeaders) {
+        let count = request.count.min(MAX_HEADERS_PER_REQUEST);
+        let headers = self
+            .chain
+            .read()
+            .unwrap()
+            .headers_after(&request.locator, count);
+        match headers {
+            Ok(headers) => self.send_to_peer(
+                &to,
+                Message::new(MESSAGE_TYPE_HEADERS, serde_json::to_vec(&headers).unwrap()),
+            ),
+            Err(err) => println!("{}", err),
+        }
+    }
+
+    fn send_blocks(&self, to: SocketAddr, request: GetBlocks) {
+        let chain = self.chain.read().unwrap();
+        let count = request.count.min(MAX_BLOCKS_PER_REQUEST);
+        let blocks: Result<Vec<Block>, String> = (request.start..)
+            .take(count as usize)
+            .take_while(|height| chain.has_block(*height))
+            .map(|height| chain.get_block(height))
+            .collect();
+        drop(chain);
+        match blocks {
+            Ok(blocks) => self.send_to_peer(
+                &to,
+                Message::new(MESSAGE_TYPE_BLOCKS, serde_json::to_vec(&blocks).unwrap()),
+            ),
+            Err(err) => println!("{}", err),
+        }
+    }
+
+    fn add_peer(&mut self, tcp_peer: TcpPeer) {
+        let addr = tcp_peer.addr;
+        let mut reader = match tcp_peer.try_clone() {
+            Ok(reader) => reader,
+            Err(err) => {
+                println!("{}", err);
+                return;
+            }
+        };
+        println!("received peer {}", addr);
+        let rpc_sender = self.rpc_sender.clone();
+        let event_sender = self.event_sender.clone();
+        thread::spawn(move || reader.read_loop(rpc_sender, event_sender));
+
+        // both sides open with their status, the peer joins peer_map once its own arrives
+        if let Err(err) = tcp_peer.send(self.status_message()) {
+            report_send_error(&self.event_sender, addr, err);
+        }
+        self.pending_peers.insert(addr, (tcp_peer, Instant::now()));
+    }
+
+    fn status(&self) -> Status {
+        let chain = self.chain.read().unwrap();
+        Status {
+            version: PROTOCOL_VERSION,
+            chain_id: chain.chain_id(),
+            genesis_hash: self.genesis_hash,
+            height: chain.height(),
+            node_id: self.node_id,
+        }
+    }
+
+    fn status_message(&self) -> Message {
+        let status = serde_json::to_vec(&self.status()).unwrap();
+        Message::new(MESSAGE_TYPE_STATUS, status)
+    }
+
+    // completes the handshake of a pending peer, or updates the status of a known one
+    fn handle_status(&mut self, from: SocketAddr, status: Status) {
+        let duplicate = self
+            .peer_status
+            .iter()
+            .any(|(addr, known)| *addr != from && known.node_id == status.node_id);
+        let checked = match status.check_compatible(&self.status()) {
+            Ok(()) if duplicate => Err("error: already connected to this node".to_string()),
+            checked => checked,
+        };
+        if let Err(err) = checked {
+            println!("refusing peer {}: {}", from, err);
+            self.remove_peer(&from);
+            return;
+        }
+
+        if let Some((peer, _)) = self.pending_peers.remove(&from) {
+            println!("peer {} connected at height {}", from, status.height);
+            self.peer_map.write().unwrap().insert(from, peer);
+        }
+        if self.peer_map.read().unwrap().contains_key(&from) {
+            self.sync.update_peer(from, status.height);
+            self.peer_status.insert(from, status);
+        }
+    }
+
+    fn expire_handshakes(&mut self) {
+        let expired: Vec<SocketAddr> = self
+            .pending_peers
+            .iter()
+            .filter(|(_, (_, connected))| connected.elapsed() > HANDSHAKE_TIMEOUT)
+            .map(|(addr, _)| *addr)
+            .collect();
+        for addr in expired {
+            println!("peer {} sent no status => disconnecting", addr);
+            self.remove_peer(&addr);
+        }
+    }
+
+    fn is_connected(&self, addr: &SocketAddr) -> bool {
+        self.pending_peers.contains_key(addr) || self.peer_map.read().unwrap().contains_key(addr)
+    }
+
+    // closes the connection, which also ends the peer's reader
+    fn remove_peer(&mut self, addr: &SocketAddr) -> bool {
+        self.peer_status.remove(addr);
+        self.sync.remove_peer(addr);
+        let peer = match self.pending_peers.remove(addr) {
+            Some((peer, _)) => Some(peer),
+            None => self.peer_map.write().unwrap().remove(addr),
+        };
+        match peer {
+            Some(peer) => {
+                let _ = peer.stream.shutdown(Shutdown::Both);
+                true
+            }
+            None => false,
+        }
+    }
+
+    // a peer can be reported more than once, by its reader and by failed sends
+    fn handle_peer_event(&mut self, event: PeerEvent) {
+        match event {
+            PeerEvent::Disconnected(addr, reason) => {
+                if self.remove_peer(&addr) {
+                    println!("peer {} disconnected: {}", addr, reason);
+                }
+            }
+        }
+    }
+
+    fn send_to_peer(&self, addr: &SocketAddr, message: Message) {
+        let peer_map = self.peer_map.read().unwrap();
+        let peer = peer_map
+            .get(addr)
+            .or_else(|| self.pending_peers.get(addr).map(|(peer, _)| peer));
+        match peer {
+            Some(peer) => {
+                if let Err(err) = peer.send(message) {
+                    report_send_error(&self.event_sender, *addr, err);
+                }
             }
+            None => println!("could not send message to unknown peer {}", addr),
         }
     }
 
@@ -118,53 +646,433 @@ impl Server {
         for addr in &self.opts.seed_nodes {
             let addr = addr.clone();
             let peer_sender = self.peer_sender.clone();
+            let codec = self.tcp_transport.codec;
             thread::spawn(move || {
                 let addr = String::from("localhost") + &addr;
-                let stream = TcpStream::connect(&addr).unwrap();
-                let tcp_peer = TcpPeer::new(stream, true);
-
-                peer_sender.send(tcp_peer).unwrap();
+                let tcp_peer = TcpStream::connect(&addr)
+                    .map_err(|e| format!("error: connecting to {}: {}", addr, e))
+                    .and_then(|stream| TcpPeer::new(stream, true, codec));
+                match tcp_peer {
+                    Ok(tcp_peer) => {
+                        let _ = peer_sender.send(tcp_peer);
+                    }
+                    Err(err) => println!("{}", err),
+                }
                 thread::sleep(Duration::from_millis(500));
             });
         }
     }
 
-    fn validator_loop(&mut self) {
+    fn validator_loop(&mut self, signer: Arc<dyn Signer>) {
         println!(
             "Starting validator loop with block time {}",
             self.opts.block_time
         );
         let blockchain = self.chain.clone();
+        let mempool = self.mempool.clone();
         let peer_map = self.peer_map.clone();
+        let event_sender = self.event_sender.clone();
+        let limits = self.opts.block_limits.unwrap_or_default();
         thread::spawn(move || loop {
             thread::sleep(Duration::from_secs(3));
             let chain = blockchain.write().unwrap();
-            let _ = create_new_block(chain);
+            let block_added = match create_new_block(chain, &mempool, &limits, &*signer) {
+                Some(block) => block,
+                None => continue,
+            };
 
             // broadcast block to peers
-
-            let mut chain = blockchain.write().unwrap();
-            let height = chain.height();
-            let block_added = chain.get_block(height).unwrap();
-            let peer_map = peer_map.read().unwrap();
-            // peer_map.clone() clones every tcp peer every time we broadcast - very inefficient
-            for (_addr, mut tcp_stream) in peer_map.clone().into_iter() {
-                let message = Message::new(MESSAGE_TYPE_BLOCK, block_added.encode().into_bytes());
-                tcp_stream.send(message.bytes());
-            }
+            let data = block_added.encode().into_bytes();
+            broadcast(&peer_map, &event_sender, MESSAGE_TYPE_BLOCK, &data, None);
         });
     }
 }
 
-fn create_new_block(mut chain: RwLockWriteGuard<Blockchain>) {
+// sends to every peer that completed the handshake, except the one the data came from
+fn broadcast(
+    peer_map: &RwLock<HashMap<SocketAddr, TcpPeer>>,
+    event_sender: &Sender<PeerEvent>,
+    header: u8,
+    data: &[u8],
+    except: Option<SocketAddr>,
+) {
+    for (addr, tcp_peer) in peer_map.read().unwrap().iter() {
+        if Some(*addr) == except {
+            continue;
+        }
+        if let Err(err) = tcp_peer.send(Message::new(header, data.to_vec())) {
+            report_send_error(event_sender, *addr, err);
+        }
+    }
+}
+
+// a failed write means the connection is gone, a message that can't be framed
+// only affects itself
+fn report_send_error(event_sender: &Sender<PeerEvent>, addr: SocketAddr, err: FrameError) {
+    println!("could not send message to peer {}: {}", addr, err);
+    if let FrameError::Io(e) = err {
+        let _ = event_sender.send(PeerEvent::Disconnected(addr, e.into()));
+    }
+}
+
+// builds a block on the head from mempool transactions, signs it and returns it once added
+fn create_new_block(
+    mut chain: RwLockWriteGuard<Blockchain>,
+    mempool: &Arc<RwLock<TxPool>>,
+    limits: &BlockLimits,
+    signer: &dyn Signer,
+) -> Option<Block> {
     let height = chain.height();
-    let h = chain.get_header(height).unwrap();
-    let block = new_block_from_prev_header(h, vec![]);
-    chain.add_block(block).unwrap();
-    println!("adding block");
+    let mut h = chain.get_header(height).unwrap();
+    let selection = select_transactions(
+        mempool.read().unwrap().pending(),
+        chain.state(),
+        &chain.sig_cache(),
+        chain.chain_id(),
+        limits,
+    );
+    if !selection.invalid.is_empty() {
+        let mut mempool = mempool.write().unwrap();
+        for hash in &selection.invalid {
+            mempool.evict(hash);
+        }
+    }
+    let mut block = new_block_from_prev_header(&mut h, selection.transactions);
+    block.sign(signer);
+    let hashes = transaction_hashes(&mut block);
+
+    let added = match chain.add_block(block.clone()) {
+        Ok(()) => {
+            println!(
+                "adding block {} with {} transactions",
+                block.hash(),
+                hashes.len()
+            );
+            remove_included(&chain, mempool, &hashes);
+            Some(block)
+        }
+        Err(err) => {
+            println!("could not add produced block: {}", err);
+            None
+        }
+    };
+    return_orphaned_transactions(&mut chain, mempool);
+    added
+}
+
+fn transaction_hashes(block: &mut Block) -> Vec<Hash> {
+    block.transactions.iter_mut().map(|tx| tx.hash()).collect()
+}
+
+// drops pooled transactions that made it into the main chain, and any other
+// whose nonce it has used since. Ones in a block that only went to a side branch stay
+fn remove_included(chain: &Blockchain, mempool: &Arc<RwLock<TxPool>>, hashes: &[Hash]) {
+    let mut mempool = mempool.write().unwrap();
+    for hash in hashes {
+        if chain.get_transaction(hash).is_ok() {
+            mempool.remove(hash);
+        }
+    }
+    mempool.prune(chain.state());
+}
+
+// transactions dropped by a reorg go back into the mempool
+fn return_orphaned_transactions(chain: &mut Blockchain, mempool: &Arc<RwLock<TxPool>>) {
+    let orphaned = chain.take_orphaned_transactions();
+    if !orphaned.is_empty() {
+        mempool
+            .write()
+            .unwrap()
+            .add_orphaned(orphaned, chain.state());
+    }
+}
+
+fn new_blockchain(opts: &ServerOpts) -> Result<Blockchain, String> {
+    let genesis = genesis_block(opts.chain_id);
+    let mut state = State::new();
+    for (address, amount) in &opts.genesis_allocations {
+        state.credit(*address, *amount);
+    }
+    let mut chain = match &opts.data_dir {
+        Some(dir) => {
+            let store = FileBlockStore::open(dir)?;
+            let recovery = store.recovery();
+            if recovery != Recovery::default() {
+                println!(
+                    "block store: dropped {} unreadable blocks and {} unindexed bytes",
+                    recovery.dropped_blocks, recovery.dropped_bytes
+                );
+            }
+            Blockchain::open(genesis, state, Box::new(store))?
+        }
+        None => Blockchain::new_with_state(genesis, state),
+    };
+    chain.set_validators(opts.validators.iter().copied());
+    Ok(chain)
 }
 
-fn genesis_block() -> Block {
-    let header = Header::new(1, "".to_string(), "".to_string(), 0, 0);
-    Block::new(header, vec![])
+fn genesis_block(chain_id: u32) -> Block {
+    let mut transactions = vec![];
+    let data_hash = calculate_data_hash(&mut transactions);
+    let header = Header::new(1, chain_id, data_hash, Hash::zero(), 0, 0);
+    Block::new(header, transactions)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+
+    fn new_server() -> Server {
+        Server::new(server_opts()).unwrap()
+    }
+
+    fn server_opts() -> ServerOpts {
+        ServerOpts {
+            listen_addr: "0".to_string(),
+            chain_id: 1,
+            data_dir: None,
+            seed_nodes: vec![],
+            validator_key: None,
+            block_time: 3,
+            rpc_decode_func: None,
+            max_frame_size: None,
+            block_limits: None,
+            max_pool_size: None,
+            max_pool_per_sender: None,
+            genesis_allocations: vec![(KeyPair::new(0).address(), 1000)],
+            validators: vec![KeyPair::new(0).address()],
+        }
+    }
+
+    // a transfer of 5 from KeyPair::new(0), which is funded at genesis
+    fn signed_transaction(nonce: u64, chain_id: u32) -> Transaction {
+        let mut tx = Transaction::new([0; 20], 5, nonce, chain_id);
+        tx.sign(&KeyPair::new(0));
+        tx
+    }
+
+    #[test]
+    fn test_new_fails_without_validator_key() {
+        let dir = std::env::temp_dir().join(format!("blockchain-server-{}", std::process::id()));
+        let mut opts = server_opts();
+        opts.validator_key = Some(ValidatorKey::Keystore {
+            dir: dir.to_string_lossy().to_string(),
+            address: [1; 20],
+            passphrase: "secret".to_string(),
+        });
+        assert!(Server::new(opts).is_err());
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_new_fails_for_key_outside_validator_set() {
+        let mut opts = server_opts();
+        opts.validator_key = Some(ValidatorKey::Seed(1));
+        assert!(Server::new(opts).is_err());
+
+        let mut opts = server_opts();
+        opts.validator_key = Some(ValidatorKey::Seed(0));
+        assert!(Server::new(opts).is_ok());
+    }
+
+    #[test]
+    fn test_process_transaction() {
+        let mut server = new_server();
+        assert_eq!(
+            server.process_transaction(None, signed_transaction(0, 1)),
+            Ok(())
+        );
+        assert_eq!(
+            server.process_transaction(None, signed_transaction(0, 1)),
+            Err(Rejection::Duplicate)
+        );
+        assert_eq!(
+            server.process_transaction(None, signed_transaction(1, 2)),
+            Err(Rejection::WrongChain {
+                expected: 1,
+                got: 2
+            })
+        );
+        // queued until nonces 1 and 2 arrive
+        assert_eq!(
+            server.process_transaction(None, signed_transaction(3, 1)),
+            Ok(())
+        );
+        let mut other = Transaction::new([1; 20], 0, 0, 1);
+        other.sign(&KeyPair::new(0));
+        assert_eq!(
+            server.process_transaction(None, other),
+            Err(Rejection::NonceInUse(0))
+        );
+        // the same data from another sender is a different transaction
+        let mut other_sender = Transaction::new([0; 20], 0, 0, 1);
+        other_sender.sign(&KeyPair::new(1));
+        assert_eq!(server.process_transaction(None, other_sender), Ok(()));
+
+        let mut tampered = signed_transaction(1, 1);
+        tampered.signature = Some("00".repeat(65));
+        assert!(matches!(
+            server.process_transaction(None, tampered),
+            Err(Rejection::InvalidSignature(_))
+        ));
+        assert_eq!(server.mempool.read().unwrap().len(), 3);
+    }
+
+    #[test]
+    fn test_ignores_messages_from_unknown_peers() {
+        let mut server = new_server();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let _client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (socket, _) = listener.accept().unwrap();
+        let peer = TcpPeer::new(socket, false, server.tcp_transport.codec).unwrap();
+        let from = peer.addr;
+        server.peer_map.write().unwrap().insert(from, peer);
+
+        let rpc = |nonce| {
+            let mut data = vec![MESSAGE_TYPE_TX];
+            data.extend(signed_transaction(nonce, 1).encode().into_bytes());
+            RPC { from, data }
+        };
+        server.handle_rpc(rpc(0));
+        assert_eq!(server.mempool.read().unwrap().len(), 1);
+
+        // once the peer is dropped, what its reader already queued is ignored
+        server.remove_peer(&from);
+        server.handle_rpc(rpc(1));
+        assert_eq!(server.mempool.read().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_process_block() {
+        let mut server = new_server();
+        let from: SocketAddr = "127.0.0.1:3000".parse().unwrap();
+        let mut genesis = server.chain.read().unwrap().get_header(0).unwrap();
+        let mut block = new_block_from_prev_header(&mut genesis, vec![]);
+        block.sign(&KeyPair::new(0));
+        let mut child = new_block_from_prev_header(&mut block.header, vec![]);
+        child.sign(&KeyPair::new(0));
+        server.sync.update_peer(from, 0);
+
+        // the child waits for its parent, then both are added
+        assert_eq!(server.process_block(from, child.clone()), Ok(()));
+        assert_eq!(
+            server.process_block(from, child.clone()),
+            Err(Rejection::Duplicate)
+        );
+        assert_eq!(server.process_block(from, block.clone()), Ok(()));
+        assert_eq!(server.chain.read().unwrap().height(), 2);
+        assert_eq!(
+            server.process_block(from, block.clone()),
+            Err(Rejection::Duplicate)
+        );
+
+        let mut other_chain = new_block_from_prev_header(&mut child.header, vec![]);
+        other_chain.header.chain_id = 2;
+        other_chain.sign(&KeyPair::new(0));
+        assert_eq!(
+            server.process_block(from, other_chain),
+            Err(Rejection::WrongChain {
+                expected: 1,
+                got: 2
+            })
+        );
+
+        let mut invalid = new_block_from_prev_header(&mut child.header, vec![]);
+        invalid.header.height = 5;
+        invalid.sign(&KeyPair::new(0));
+        assert!(matches!(
+            server.process_block(from, invalid),
+            Err(Rejection::InvalidBlock(_))
+        ));
+
+        // blocks from keys outside the validator set are refused
+        let mut unknown = new_block_from_prev_header(&mut child.header, vec![]);
+        unknown.sign(&KeyPair::new(1));
+        assert!(matches!(
+            server.process_block(from, unknown),
+            Err(Rejection::InvalidBlock(_))
+        ));
+
+        // only the accepted blocks raised the peer's height, so it isn't synced from
+        let chain = server.chain.read().unwrap();
+        assert!(server.sync.poll(&chain, Instant::now()).is_empty());
+    }
+
+    #[test]
+    fn test_create_new_block() {
+        let mut server = new_server();
+        for nonce in 0..3 {
+            server
+                .process_transaction(None, signed_transaction(nonce, 1))
+                .unwrap();
+        }
+        let limits = BlockLimits {
+            max_transactions: 2,
+            ..BlockLimits::default()
+        };
+
+        let chain = server.chain.write().unwrap();
+        let block = create_new_block(chain, &server.mempool, &limits, &KeyPair::new(0)).unwrap();
+        assert_eq!(block.transactions.len(), 2);
+        assert_eq!(block.header.validator, KeyPair::new(0).address());
+        assert!(block.signature.is_some());
+        assert_eq!(server.mempool.read().unwrap().len(), 1);
+
+        let chain = server.chain.write().unwrap();
+        let block = create_new_block(chain, &server.mempool, &limits, &KeyPair::new(0)).unwrap();
+        assert_eq!(block.transactions.len(), 1);
+        assert_eq!(block.transactions[0].data.nonce, 2);
+        assert_eq!(server.mempool.read().unwrap().len(), 0);
+        assert_eq!(server.chain.read().unwrap().height(), 2);
+    }
+
+    #[test]
+    fn test_transfer() {
+        let mut server = new_server();
+        let sender = KeyPair::new(0).address();
+        let mut tx = Transaction::new([1; 20], 250, 0, 1).with_fee(10);
+        tx.sign(&KeyPair::new(0));
+        server.process_transaction(None, tx).unwrap();
+
+        let chain = server.chain.write().unwrap();
+        let limits = BlockLimits::default();
+        let block = create_new_block(chain, &server.mempool, &limits, &KeyPair::new(0)).unwrap();
+        assert_eq!(block.transactions.len(), 1);
+        let chain = server.chain.read().unwrap();
+        assert_eq!(chain.balance_of(&sender), 740);
+        assert_eq!(chain.balance_of(&[1; 20]), 250);
+
+        // more than is left can't be pooled
+        drop(chain);
+        let mut overdraw = Transaction::new([1; 20], 741, 1, 1);
+        overdraw.sign(&KeyPair::new(0));
+        assert!(server.process_transaction(None, overdraw).is_err());
+    }
+
+    #[test]
+    fn test_create_new_block_evicts_invalid() {
+        let mut server = new_server();
+        // admitted against a state where the senders could pay, unlike the chain's
+        let mut funded = State::new();
+        for key in 1..3 {
+            funded.credit(KeyPair::new(key).address(), 1000);
+            let mut tx = Transaction::new([0; 20], 5, 0, 1).with_fee(100);
+            tx.sign(&KeyPair::new(key));
+            server.mempool.write().unwrap().add(tx, &funded).unwrap();
+        }
+        server
+            .process_transaction(None, signed_transaction(0, 1))
+            .unwrap();
+        let limits = BlockLimits {
+            max_transactions: 2,
+            ..BlockLimits::default()
+        };
+
+        let chain = server.chain.write().unwrap();
+        let block = create_new_block(chain, &server.mempool, &limits, &KeyPair::new(0)).unwrap();
+        assert_eq!(block.transactions.len(), 1);
+        assert_eq!(block.transactions[0].from, KeyPair::new(0).address());
+        assert_eq!(server.mempool.read().unwrap().len(), 0);
+    }
 }
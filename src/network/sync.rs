@@ -0,0 +1,902 @@
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+use super::rpc::{GetBlocks, GetHeaders};
+use crate::core::block::{Block, Header};
+use crate::core::blockchain::Blockchain;
+use crate::types::address::encode_address;
+
+pub const MAX_HEADERS_PER_REQUEST: u32 = 2000;
+// headers are only downloaded this far past the chain, more are asked for as
+// their blocks are added
+pub const MAX_SYNC_HEADERS: usize = 16 * MAX_HEADERS_PER_REQUEST as usize;
+pub const MAX_BLOCKS_PER_REQUEST: u32 = 16;
+// block requests a single peer may have outstanding
+const MAX_REQUESTS_PER_PEER: usize = 4;
+// bodies are only fetched this far past the next block to add, which bounds
+// how many downloaded blocks are held in memory
+const MAX_BLOCKS_AHEAD: usize = 512;
+pub const REQUEST_TIMEOUT: Duration = Duration::from_secs(15);
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+const PROGRESS_INTERVAL: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SyncRequest {
+    Headers(GetHeaders),
+    Blocks(GetBlocks),
+}
+
+struct BlockRequest {
+    peer: SocketAddr,
+    count: u32,
+    sent: Instant,
+}
+
+// Syncer catches the node up with peers that are ahead of it. Headers are
+// downloaded first, from the peer furthest ahead, and must form a chain from a
+// block we know. Their bodies are then fetched in ranges from every peer that
+// has them and handed out in height order, each checked against its header.
+// Downloaded headers are saved so a restarted node carries on where it stopped.
+pub struct Syncer {
+    // one header per line, appended as they arrive
+    headers_path: Option<PathBuf>,
+    // lines in the file, including headers whose blocks have been added since
+    saved: usize,
+    // validated headers whose blocks are not in the chain yet, in height order
+    headers: VecDeque<Header>,
+    // height of the first header of each answer => the peer that sent it.
+    // Headers loaded from the file have none
+    header_peers: BTreeMap<u32, SocketAddr>,
+    // the peer asked for headers, when and how many
+    header_request: Option<(SocketAddr, Instant, u32)>,
+    // the best known height of each peer: from its status, raised by blocks it
+    // relays and lowered when it runs out of headers to give
+    peer_heights: HashMap<SocketAddr, u32>,
+    // first height => request
+    block_requests: HashMap<u32, BlockRequest>,
+    // bodies waiting for the blocks before them, with the peer that sent them
+    blocks: BTreeMap<u32, (Block, SocketAddr)>,
+    last_poll: Option<Instant>,
+    last_progress: Option<Instant>,
+}
+
+impl Syncer {
+    // headers_path is where downloaded headers are kept, none for in-memory chains
+    pub fn new(headers_path: Option<PathBuf>, chain: &Blockchain) -> Self {
+        let mut syncer = Syncer {
+            headers_path,
+            saved: 0,
+            headers: VecDeque::new(),
+            header_peers: BTreeMap::new(),
+            header_request: None,
+            peer_heights: HashMap::new(),
+            block_requests: HashMap::new(),
+            blocks: BTreeMap::new(),
+            last_poll: None,
+            last_progress: None,
+        };
+        if let Err(err) = syncer.load(chain) {
+            println!("sync: {} => downloading headers again", err);
+            syncer.reset();
+        }
+        syncer
+    }
+
+    fn load(&mut self, chain: &Blockchain) -> Result<(), String> {
+        let path = match &self.headers_path {
+            Some(path) if path.exists() => path,
+            _ => return Ok(()),
+        };
+        let data =
+            fs::read_to_string(path).map_err(|e| format!("error: reading sync headers: {}", e))?;
+        let lines: Vec<&str> = data.lines().collect();
+        for (i, line) in lines.iter().enumerate() {
+            match serde_json::from_str(line) {
+                Ok(header) => self.headers.push_back(header),
+                // the last line may have been cut short by a crash while appending
+                Err(_) if i + 1 == lines.len() && !data.ends_with('\n') => {}
+                Err(_) => return Err(format!("error: invalid sync headers {}", path.display())),
+            }
+        }
+        self.saved = lines.len();
+        self.prune(chain);
+
+        // the rest must still extend a block we know
+        if let Some(first) = self.headers.front() {
+            let connected = chain
+                .get_known_header(&first.prev_block_hash)
+                .is_some_and(|parent| parent.height + 1 == first.height);
+            if !connected {
+                return Err("error: saved sync headers don't connect to the chain".to_string());
+            }
+            println!(
+                "sync: resuming with headers up to height {}",
+                self.headers.back().unwrap().height
+            );
+        }
+        Ok(())
+    }
+
+    // adds the last count headers to the file. Once most of its lines are for
+    // blocks already added, the file is written anew instead
+    fn save(&mut self, count: usize) {
+        let path = match &self.headers_path {
+            Some(path) => path,
+            None => return,
+        };
+        if self.saved + count > 2 * self.headers.len() + MAX_HEADERS_PER_REQUEST as usize {
+            return self.rewrite();
+        }
+        let mut lines = String::new();
+        for header in self.headers.iter().skip(self.headers.len() - count) {
+            lines.push_str(&header.encode());
+            lines.push('\n');
+        }
+        let written = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .and_then(|mut f| {
+                f.write_all(lines.as_bytes())?;
+                f.sync_all()
+            });
+        match written {
+            Ok(()) => self.saved += count,
+            Err(e) => println!("sync: error saving headers: {}", e),
+        }
+    }
+
+    // written to a temporary file first so a crash never leaves a partial file
+    fn rewrite(&mut self) {
+        let path = match &self.headers_path {
+            Some(path) => path,
+            None => return,
+        };
+        let tmp = path.with_extension("tmp");
+        let mut lines = String::new();
+        for header in &self.headers {
+            lines.push_str(&header.encode());
+            lines.push('\n');
+        }
+        let written = fs::File::create(&tmp)
+            .and_then(|mut f| {
+                f.write_all(lines.as_bytes())?;
+                f.sync_all()
+            })
+            .and_then(|_| fs::rename(&tmp, path));
+        match written {
+            Ok(()) => self.saved = self.headers.len(),
+            Err(e) => println!("sync: error saving headers: {}", e),
+        }
+    }
+
+    // drops everything downloaded
+    pub fn reset(&mut self) {
+        self.headers.clear();
+        self.header_peers.clear();
+        self.block_requests.clear();
+        self.blocks.clear();
+        self.header_request = None;
+        self.rewrite();
+    }
+
+    // a downloaded block was refused by the chain, so the headers it matched can't
+    // be trusted. Everything is dropped and the peers that sent the block and its
+    // header are forgotten, they are returned to be disconnected
+    pub fn block_rejected(&mut self, height: u32, from: SocketAddr) -> Vec<SocketAddr> {
+        let mut peers = vec![from];
+        if let Some(peer) = self.header_peer(height) {
+            if peer != from {
+                peers.push(peer);
+            }
+        }
+        self.reset();
+        for peer in &peers {
+            self.remove_peer(peer);
+        }
+        peers
+    }
+
+    fn header_peer(&self, height: u32) -> Option<SocketAddr> {
+        self.header_peers
+            .range(..=height)
+            .next_back()
+            .map(|(_, peer)| *peer)
+    }
+
+    pub fn is_syncing(&self) -> bool {
+        !self.headers.is_empty() || self.header_request.is_some()
+    }
+
+    // the height being synced to, once headers are known
+    pub fn target_height(&self) -> Option<u32> {
+        self.headers.back().map(|header| header.height)
+    }
+
+    // sets a peer's height from its status
+    pub fn update_peer(&mut self, addr: SocketAddr, height: u32) {
+        self.peer_heights.insert(addr, height);
+    }
+
+    // raises a peer's height when it relays a block
+    pub fn saw_block(&mut self, addr: SocketAddr, height: u32) {
+        if let Some(known) = self.peer_heights.get_mut(&addr) {
+            *known = (*known).max(height);
+        }
+    }
+
+    // whatever was asked of the peer is asked of others on the next poll
+    pub fn remove_peer(&mut self, addr: &SocketAddr) {
+        self.peer_heights.remove(addr);
+        self.block_requests
+            .retain(|_, request| request.peer != *addr);
+        if self
+            .header_request
+            .is_some_and(|(peer, _, _)| peer == *addr)
+        {
+            self.header_request = None;
+        }
+    }
+
+    // the requests to send now, at most once per POLL_INTERVAL
+    pub fn poll(&mut self, chain: &Blockchain, now: Instant) -> Vec<(SocketAddr, SyncRequest)> {
+        if self
+            .last_poll
+            .is_some_and(|last| now.duration_since(last) < POLL_INTERVAL)
+        {
+            return vec![];
+        }
+        self.last_poll = Some(now);
+        self.expire(now);
+        self.prune(chain);
+
+        let mut requests = vec![];
+        let tip = self.target_height().unwrap_or(chain.height());
+        let room = MAX_SYNC_HEADERS.saturating_sub(self.headers.len()) as u32;
+        if self.header_request.is_none() && room > 0 {
+            let ahead = self
+                .peer_heights
+                .iter()
+                .filter(|(_, height)| **height > tip)
+                .max_by_key(|(addr, height)| (**height, **addr))
+                .map(|(addr, _)| *addr);
+            if let Some(peer) = ahead {
+                let mut locator = chain.block_locator().unwrap_or_default();
+                if let Some(last) = self.headers.back_mut() {
+                    locator.insert(0, last.hash());
+                }
+                let count = room.min(MAX_HEADERS_PER_REQUEST);
+                self.header_request = Some((peer, now, count));
+                requests.push((peer, SyncRequest::Headers(GetHeaders { locator, count })));
+            }
+        }
+        requests.extend(self.request_blocks(now));
+        self.report_progress(chain, now);
+        requests
+    }
+
+    fn expire(&mut self, now: Instant) {
+        if let Some((peer, sent, _)) = self.header_request {
+            if now.duration_since(sent) > REQUEST_TIMEOUT {
+                println!("sync: peer {} did not send headers => retrying", peer);
+                self.header_request = None;
+            }
+        }
+        self.block_requests.retain(|start, request| {
+            let expired = now.duration_since(request.sent) > REQUEST_TIMEOUT;
+            if expired {
+                println!(
+                    "sync: peer {} did not send blocks from {} => retrying",
+                    request.peer, start
+                );
+            }
+            !expired
+        });
+    }
+
+    // splits the heights that are neither downloaded nor asked for into ranges
+    // and gives each to the peer with the fewest requests that has it
+    fn request_blocks(&mut self, now: Instant) -> Vec<(SocketAddr, SyncRequest)> {
+        let requested: HashSet<u32> = self
+            .block_requests
+            .iter()
+            .flat_map(|(start, request)| *start..*start + request.count)
+            .collect();
+        let mut ranges: Vec<(u32, u32)> = vec![];
+        let mut extend = false;
+        for header in self.headers.iter().take(MAX_BLOCKS_AHEAD) {
+            let height = header.height;
+            if self.blocks.contains_key(&height) || requested.contains(&height) {
+                extend = false;
+                continue;
+            }
+            match ranges.last_mut() {
+                Some((_, count)) if extend && *count < MAX_BLOCKS_PER_REQUEST => *count += 1,
+                _ => ranges.push((height, 1)),
+            }
+            extend = true;
+        }
+
+        let mut in_flight: HashMap<SocketAddr, usize> = HashMap::new();
+        for request in self.block_requests.values() {
+            *in_flight.entry(request.peer).or_default() += 1;
+        }
+        let mut requests = vec![];
+        for (start, count) in ranges {
+            let end = start + count - 1;
+            let peer = self
+                .peer_heights
+                .iter()
+                .filter(|(addr, height)| {
+                    **height >= end
+                        && in_flight.get(*addr).copied().unwrap_or(0) < MAX_REQUESTS_PER_PEER
+                })
+                .min_by_key(|(addr, _)| (in_flight.get(*addr).copied().unwrap_or(0), **addr))
+                .map(|(addr, _)| *addr);
+            let peer = match peer {
+                Some(peer) => peer,
+                None => continue,
+            };
+            *in_flight.entry(peer).or_default() += 1;
+            self.block_requests.insert(
+                start,
+                BlockRequest {
+                    peer,
+                    count,
+                    sent: now,
+                },
+            );
+            requests.push((peer, SyncRequest::Blocks(GetBlocks { start, count })));
+        }
+        requests
+    }
+
+    // checks the headers link up from a block we know. Headers branching off
+    // somewhere other than the end of ours replace them if they reach further
+    pub fn on_headers(
+        &mut self,
+        from: SocketAddr,
+        mut headers: Vec<Header>,
+        chain: &Blockchain,
+    ) -> Result<(), String> {
+        let count = match self.header_request {
+            Some((peer, _, count)) if peer == from => count,
+            _ => return Err(format!("error: unrequested headers from {}", from)),
+        };
+        self.header_request = None;
+        if headers.len() > count as usize {
+            return Err(format!("error: too many headers from {}", from));
+        }
+
+        let tip = self.target_height().unwrap_or(chain.height());
+        let parent_hash = match headers.first() {
+            Some(first) => first.prev_block_hash,
+            None => {
+                // the peer has nothing after our tip, so it isn't ahead of us
+                self.peer_heights.insert(from, tip);
+                return Ok(());
+            }
+        };
+        let last_hash = self.headers.back_mut().map(|last| last.hash());
+        let (mut prev, replace) = match self.headers.back() {
+            Some(last) if last_hash == Some(parent_hash) => (last.clone(), false),
+            _ => match chain.get_known_header(&parent_hash) {
+                Some(parent) => (parent, !self.headers.is_empty()),
+                None => {
+                    return Err(format!(
+                        "error: headers from {} don't connect to a known block",
+                        from
+                    ))
+                }
+            },
+        };
+        for header in headers.iter_mut() {
+            if header.chain_id != chain.chain_id() {
+                return Err(format!(
+                    "error: header {} from {} is for chain {}",
+                    header.hash(),
+                    from,
+                    header.chain_id
+                ));
+            }
+            if !chain.is_validator(&header.validator) {
+                return Err(format!(
+                    "error: header {} from {} names {} which is not a validator",
+                    header.hash(),
+                    from,
+                    encode_address(&header.validator)
+                ));
+            }
+            if header.height != prev.height + 1 || header.prev_block_hash != prev.hash() {
+                return Err(format!(
+                    "error: header {} from {} does not follow {}",
+                    header.hash(),
+                    from,
+                    prev.hash()
+                ));
+            }
+            prev = header.clone();
+        }
+
+        // a short answer means the peer has no more
+        let last = prev.height;
+        if headers.len() < count as usize {
+            self.peer_heights.insert(from, last);
+        } else {
+            self.saw_block(from, last);
+        }
+
+        if replace {
+            if last <= tip {
+                // not asked again until it relays a block past that
+                self.peer_heights.insert(from, last);
+                println!(
+                    "sync: peer {} is on a branch from height {} that is no longer than ours",
+                    from, headers[0].height
+                );
+                return Ok(());
+            }
+            println!(
+                "sync: peer {} is on a longer branch from height {}",
+                from, headers[0].height
+            );
+            self.headers.clear();
+            self.header_peers.clear();
+            self.block_requests.clear();
+            self.blocks.clear();
+            self.header_peers.insert(headers[0].height, from);
+            self.headers.extend(headers);
+            self.rewrite();
+            return Ok(());
+        }
+        let added = headers.len();
+        self.header_peers.insert(headers[0].height, from);
+        self.headers.extend(headers);
+        self.save(added);
+        Ok(())
+    }
+
+    // keeps the blocks that match their header. A short answer is fine, the
+    // missing blocks are asked for again
+    pub fn on_blocks(&mut self, from: SocketAddr, blocks: Vec<Block>) -> Result<(), String> {
+        let start = match blocks.first() {
+            Some(block) => block.header.height,
+            // left to time out, as it can't be told which request it answers
+            None => return Ok(()),
+        };
+        let count = match self.block_requests.get(&start) {
+            Some(request) if request.peer == from => request.count,
+            _ => return Err(format!("error: unrequested blocks from {}", from)),
+        };
+        self.block_requests.remove(&start);
+        if blocks.len() > count as usize {
+            return Err(format!("error: too many blocks from {}", from));
+        }
+
+        for (height, mut block) in (start..).zip(blocks) {
+            if block.header.height != height {
+                return Err(format!(
+                    "error: block {} from {} has height {} => expected {}",
+                    block.hash(),
+                    from,
+                    block.header.height,
+                    height
+                ));
+            }
+            // blocks already added in the meantime are no longer needed
+            let base = match self.headers.front() {
+                Some(first) if first.height <= height => first.height,
+                _ => continue,
+            };
+            let header = match self.headers.get_mut((height - base) as usize) {
+                Some(header) => header,
+                None => continue,
+            };
+            if block.hash() != header.hash() {
+                return Err(format!(
+                    "error: block {} from {} does not match header {}",
+                    block.hash(),
+                    from,
+                    header.hash()
+                ));
+            }
+            self.blocks.insert(height, (block, from));
+        }
+        Ok(())
+    }
+
+    // the downloaded blocks that follow on from the chain, in height order, each
+    // with the peer that sent it
+    pub fn take_ready(&mut self, chain: &Blockchain) -> Vec<(Block, SocketAddr)> {
+        self.prune(chain);
+        let mut ready = vec![];
+        while let Some(block) = self
+            .headers
+            .front()
+            .and_then(|header| self.blocks.remove(&header.height))
+        {
+            self.headers.pop_front();
+            ready.push(block);
+        }
+        ready
+    }
+
+    // forgets headers whose blocks reached the chain some other way
+    fn prune(&mut self, chain: &Blockchain) {
+        let mut known = 0;
+        while known < self.headers.len() && chain.is_known(&self.headers[known].hash()) {
+            known += 1;
+        }
+        if known > 0 {
+            let next = self.headers[known - 1].height + 1;
+            self.headers.drain(..known);
+            self.blocks = self.blocks.split_off(&next);
+            // the sender of the first remaining header is kept
+            let peer = self.header_peer(next);
+            self.header_peers = self.header_peers.split_off(&next);
+            if let Some(peer) = peer.filter(|_| !self.headers.is_empty()) {
+                self.header_peers.entry(next).or_insert(peer);
+            }
+        }
+    }
+
+    fn report_progress(&mut self, chain: &Blockchain, now: Instant) {
+        let target = match self.target_height() {
+            Some(target) => target,
+            None => return,
+        };
+        if self
+            .last_progress
+            .is_some_and(|last| now.duration_since(last) < PROGRESS_INTERVAL)
+        {
+            return;
+        }
+        self.last_progress = Some(now);
+        println!(
+            "sync: height {} of {} => {} blocks downloaded, {} requests outstanding",
+            chain.height(),
+            target,
+            self.blocks.len(),
+            self.block_requests.len()
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::block::random_block;
+    use crate::core::state::State;
+    use crate::crypto::keypair::KeyPair;
+    use crate::types::hash::Hash;
+
+    fn new_chain(height: u32) -> Blockchain {
+        new_branch(height, 0)
+    }
+
+    // chains with another timestamp share only the genesis block
+    fn new_branch(height: u32, timestamp: i64) -> Blockchain {
+        let mut state = State::new();
+        state.credit(KeyPair::new(0).address(), 1000);
+        let mut chain = Blockchain::new_with_state(random_block(0, Hash::zero()), state);
+        chain.set_validators([KeyPair::new(0).address()]);
+        for height in 1..=height {
+            let prev_hash = chain.head_hash().unwrap();
+            let mut block = random_block(height, prev_hash);
+            block.header.timestamp = timestamp;
+            block.sign(&KeyPair::new(0));
+            chain.add_block(block).unwrap();
+        }
+        chain
+    }
+
+    // answers the requests from the given chain, as a peer would
+    fn answer(
+        syncer: &mut Syncer,
+        chain: &mut Blockchain,
+        peer_chain: &Blockchain,
+        requests: Vec<(SocketAddr, SyncRequest)>,
+    ) {
+        for (peer, request) in requests {
+            match request {
+                SyncRequest::Headers(request) => {
+                    let headers = peer_chain
+                        .headers_after(&request.locator, request.count)
+                        .unwrap();
+                    syncer.on_headers(peer, headers, chain).unwrap();
+                }
+                SyncRequest::Blocks(request) => {
+                    let blocks = (request.start..request.start + request.count)
+                        .map(|height| peer_chain.get_block(height).unwrap())
+                        .collect();
+                    syncer.on_blocks(peer, blocks).unwrap();
+                }
+            }
+        }
+        for (block, _) in syncer.take_ready(chain) {
+            chain.add_block(block).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_sync_from_peers() {
+        let peer_chain = new_chain(40);
+        let mut chain = new_chain(0);
+        let mut syncer = Syncer::new(None, &chain);
+        let (a, b) = (
+            "127.0.0.1:3000".parse().unwrap(),
+            "127.0.0.1:4000".parse().unwrap(),
+        );
+        syncer.update_peer(a, 40);
+        syncer.update_peer(b, 40);
+
+        // headers come from one peer, then the bodies are spread over both
+        let mut now = Instant::now();
+        let requests = syncer.poll(&chain, now);
+        assert_eq!(requests.len(), 1);
+        assert!(matches!(requests[0].1, SyncRequest::Headers(_)));
+        answer(&mut syncer, &mut chain, &peer_chain, requests);
+        assert_eq!(syncer.target_height(), Some(40));
+
+        now += POLL_INTERVAL;
+        let requests = syncer.poll(&chain, now);
+        let peers: HashSet<SocketAddr> = requests.iter().map(|(peer, _)| *peer).collect();
+        assert_eq!(requests.len(), 3);
+        assert_eq!(peers.len(), 2);
+        answer(&mut syncer, &mut chain, &peer_chain, requests);
+        assert_eq!(chain.height(), 40);
+        assert_eq!(chain.head_hash(), peer_chain.head_hash());
+
+        now += POLL_INTERVAL;
+        assert!(syncer.poll(&chain, now).is_empty());
+        assert!(!syncer.is_syncing());
+    }
+
+    #[test]
+    fn test_header_window() {
+        let chain = new_chain(0);
+        let mut syncer = Syncer::new(None, &chain);
+        let peer: SocketAddr = "127.0.0.1:3000".parse().unwrap();
+        syncer.update_peer(peer, u32::MAX);
+        let header = new_chain(1).get_header(1).unwrap();
+        syncer
+            .headers
+            .extend(std::iter::repeat_n(header, MAX_SYNC_HEADERS - 10));
+
+        // only as many headers as still fit are asked for
+        let mut now = Instant::now();
+        let requests = syncer.poll(&chain, now);
+        match &requests[0].1 {
+            SyncRequest::Headers(request) => assert_eq!(request.count, 10),
+            request => panic!("unexpected request {:?}", request),
+        }
+        let too_many = vec![chain.get_header(0).unwrap(); 11];
+        assert!(syncer
+            .on_headers(peer, too_many, &chain)
+            .unwrap_err()
+            .contains("too many"));
+
+        let header = syncer.headers[0].clone();
+        syncer.headers.extend(std::iter::repeat_n(header, 10));
+        now += POLL_INTERVAL;
+        assert!(syncer
+            .poll(&chain, now)
+            .iter()
+            .all(|(_, request)| !matches!(request, SyncRequest::Headers(_))));
+    }
+
+    #[test]
+    fn test_switch_to_longer_branch() {
+        let chain = new_chain(0);
+        let mut syncer = Syncer::new(None, &chain);
+        let (a, b) = (
+            "127.0.0.1:3000".parse().unwrap(),
+            "127.0.0.1:4000".parse().unwrap(),
+        );
+        let branch_a = new_chain(10);
+        let headers = branch_a
+            .headers_after(&chain.block_locator().unwrap(), 10)
+            .unwrap();
+        syncer.update_peer(a, 10);
+        let mut now = Instant::now();
+        syncer.poll(&chain, now);
+        syncer.on_headers(a, headers, &chain).unwrap();
+        assert_eq!(syncer.target_height(), Some(10));
+
+        // a peer on a shorter branch doesn't wipe the headers
+        let ask_b = |syncer: &mut Syncer, branch: &Blockchain, now: Instant| {
+            syncer.update_peer(b, 100);
+            let requests = syncer.poll(&chain, now);
+            let request = requests
+                .into_iter()
+                .find_map(|(peer, request)| match request {
+                    SyncRequest::Headers(request) if peer == b => Some(request),
+                    _ => None,
+                })
+                .unwrap();
+            let headers = branch
+                .headers_after(&request.locator, request.count)
+                .unwrap();
+            syncer.on_headers(b, headers, &chain).unwrap();
+        };
+        now += POLL_INTERVAL;
+        ask_b(&mut syncer, &new_branch(5, 1), now);
+        assert_eq!(syncer.target_height(), Some(10));
+        assert_eq!(syncer.peer_heights[&b], 5);
+
+        now += POLL_INTERVAL;
+        let branch_b = new_branch(15, 1);
+        ask_b(&mut syncer, &branch_b, now);
+        assert_eq!(syncer.target_height(), Some(15));
+        assert_eq!(
+            syncer.headers[0].hash(),
+            branch_b.get_header(1).unwrap().hash()
+        );
+    }
+
+    #[test]
+    fn test_invalid_answers() {
+        let peer_chain = new_chain(5);
+        let chain = new_chain(0);
+        let mut syncer = Syncer::new(None, &chain);
+        let peer: SocketAddr = "127.0.0.1:3000".parse().unwrap();
+        syncer.update_peer(peer, 5);
+
+        let genesis = chain.block_locator().unwrap();
+        let headers = peer_chain.headers_after(&genesis, 5).unwrap();
+        assert!(syncer.on_headers(peer, headers.clone(), &chain).is_err());
+
+        // headers must link up
+        syncer.poll(&chain, Instant::now());
+        let mut gap = headers.clone();
+        gap.remove(2);
+        assert!(syncer.on_headers(peer, gap, &chain).is_err());
+        assert!(!syncer.is_syncing());
+
+        let mut now = Instant::now() + POLL_INTERVAL;
+        syncer.poll(&chain, now);
+        syncer.on_headers(peer, headers, &chain).unwrap();
+
+        // a block that doesn't match its header is refused
+        now += POLL_INTERVAL;
+        let requests = syncer.poll(&chain, now);
+        assert_eq!(requests.len(), 1);
+        let mut wrong = random_block(1, Hash::zero());
+        wrong.header.prev_block_hash = chain.head_hash().unwrap();
+        wrong.header.timestamp = 1;
+        assert!(syncer.on_blocks(peer, vec![wrong]).is_err());
+        assert!(syncer.take_ready(&chain).is_empty());
+
+        // and asked for again once the request times out
+        now += REQUEST_TIMEOUT + POLL_INTERVAL;
+        assert_eq!(syncer.poll(&chain, now).len(), 1);
+        let unasked = peer_chain.get_block(3).unwrap();
+        assert!(syncer.on_blocks(peer, vec![unasked]).is_err());
+    }
+
+    // blocks with valid looking headers naming the validator, but whose
+    // signature is not the validator's
+    fn forged_branch(height: u32) -> Vec<Block> {
+        let mut blocks: Vec<Block> = vec![];
+        let mut prev_hash = new_chain(0).head_hash().unwrap();
+        for height in 1..=height {
+            let mut block = random_block(height, prev_hash);
+            block.header.timestamp = 1;
+            block.sign(&KeyPair::new(0));
+            block.signature.as_mut().unwrap().signature = "00".repeat(65);
+            prev_hash = block.hash();
+            blocks.push(block);
+        }
+        blocks
+    }
+
+    #[test]
+    fn test_lying_peer() {
+        let honest_chain = new_chain(5);
+        let mut chain = new_chain(0);
+        let mut syncer = Syncer::new(None, &chain);
+        let (honest, liar) = (
+            "127.0.0.1:3000".parse().unwrap(),
+            "127.0.0.1:4000".parse().unwrap(),
+        );
+        syncer.update_peer(honest, 5);
+        syncer.update_peer(liar, 20);
+
+        // headers naming a key outside the validator set are refused outright
+        let mut now = Instant::now();
+        let requests = syncer.poll(&chain, now);
+        assert_eq!(requests[0].0, liar);
+        let mut outsider = random_block(1, chain.head_hash().unwrap());
+        outsider.sign(&KeyPair::new(1));
+        assert!(syncer
+            .on_headers(liar, vec![outsider.header], &chain)
+            .unwrap_err()
+            .contains("not a validator"));
+
+        // forged headers reach further than the honest peer, so they are followed
+        now += POLL_INTERVAL;
+        let requests = syncer.poll(&chain, now);
+        assert_eq!(requests[0].0, liar);
+        let mut forged = forged_branch(20);
+        let headers = forged.iter().map(|block| block.header.clone()).collect();
+        syncer.on_headers(liar, headers, &chain).unwrap();
+        assert_eq!(syncer.target_height(), Some(20));
+
+        now += POLL_INTERVAL;
+        for (peer, request) in syncer.poll(&chain, now) {
+            assert_eq!(peer, liar);
+            if let SyncRequest::Blocks(request) = request {
+                let start = request.start as usize - 1;
+                let blocks = forged[start..start + request.count as usize].to_vec();
+                syncer.on_blocks(liar, blocks).unwrap();
+            }
+        }
+        let (block, from) = syncer.take_ready(&chain).swap_remove(0);
+        let height = block.header.height;
+        assert!(chain.add_block(block).is_err());
+
+        // the liar is forgotten, so sync moves on to the honest peer
+        assert_eq!(syncer.block_rejected(height, from), vec![liar]);
+        assert!(!syncer.peer_heights.contains_key(&liar));
+        assert!(!syncer.is_syncing());
+        for _ in 0..2 {
+            now += POLL_INTERVAL;
+            let requests = syncer.poll(&chain, now);
+            assert!(requests.iter().all(|(peer, _)| *peer == honest));
+            answer(&mut syncer, &mut chain, &honest_chain, requests);
+        }
+        assert_eq!(chain.height(), 5);
+        assert_eq!(chain.head_hash(), honest_chain.head_hash());
+        assert!(forged[0].verify().is_err());
+    }
+
+    #[test]
+    fn test_resume_after_restart() {
+        let dir = std::env::temp_dir().join(format!("blockchain-sync-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("sync-headers.jsonl");
+
+        let peer_chain = new_chain(10);
+        let mut chain = new_chain(0);
+        let peer: SocketAddr = "127.0.0.1:3000".parse().unwrap();
+        {
+            let mut syncer = Syncer::new(Some(path.clone()), &chain);
+            syncer.update_peer(peer, 10);
+            let requests = syncer.poll(&chain, Instant::now());
+            answer(&mut syncer, &mut chain, &peer_chain, requests);
+        }
+        // one header per line
+        assert_eq!(fs::read_to_string(&path).unwrap().lines().count(), 10);
+
+        // the restarted node already has three of the blocks
+        for height in 1..=3 {
+            chain
+                .add_block(peer_chain.get_block(height).unwrap())
+                .unwrap();
+        }
+        let mut syncer = Syncer::new(Some(path.clone()), &chain);
+        assert_eq!(syncer.target_height(), Some(10));
+        syncer.update_peer(peer, 10);
+        let requests = syncer.poll(&chain, Instant::now());
+        assert!(requests
+            .iter()
+            .all(|(_, request)| matches!(request, SyncRequest::Blocks(_))));
+        answer(&mut syncer, &mut chain, &peer_chain, requests);
+        assert_eq!(chain.height(), 10);
+
+        // saved headers from a branch whose start the chain doesn't have are dropped
+        let branch = new_branch(5, 1);
+        let lines: String = (2..=5)
+            .map(|height| branch.get_header(height).unwrap().encode() + "\n")
+            .collect();
+        fs::write(&path, lines).unwrap();
+        assert!(!Syncer::new(Some(path.clone()), &new_chain(0)).is_syncing());
+        assert!(fs::read_to_string(&path).unwrap().is_empty());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
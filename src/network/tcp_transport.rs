@@ -1,82 +1,200 @@
 use std::{
-    io::{Read, Write},
-    net::{TcpListener, TcpStream},
+    fmt, io,
+    net::{Shutdown, SocketAddr, TcpListener, TcpStream},
     sync::{mpsc::Sender, Arc, Mutex},
     thread,
+    time::Duration,
 };
 
-use crate::network::rpc::RPC;
+use crate::network::frame::{FrameCodec, FrameError};
+use crate::network::rpc::{Message, RPC};
 
-pub struct TcpPeer {
-    pub stream: TcpStream,
-    pub outgoing: bool,
+// how long a frame may take to arrive once it has started, and a send may block,
+// before the peer is considered gone. Idle peers are kept
+pub const PEER_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DisconnectReason {
+    // the peer closed the connection between frames
+    Closed,
+    Reset,
+    Timeout,
+    // the peer sent something that isn't a valid frame
+    Protocol(String),
+    // the node stopped reading from the peer
+    Shutdown,
+    Error(String),
 }
 
-impl TcpPeer {
-    pub fn new(stream: TcpStream, outgoing: bool) -> Self {
-        Self {
-            stream: stream,
-            outgoing: outgoing,
+impl From<io::Error> for DisconnectReason {
+    fn from(e: io::Error) -> Self {
+        match e.kind() {
+            io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock => DisconnectReason::Timeout,
+            io::ErrorKind::ConnectionReset
+            | io::ErrorKind::ConnectionAborted
+            | io::ErrorKind::BrokenPipe
+            | io::ErrorKind::UnexpectedEof => DisconnectReason::Reset,
+            _ => DisconnectReason::Error(e.to_string()),
         }
     }
+}
 
-    pub fn send(&mut self, data: Vec<u8>) {
-        self.stream.write_all(&data).unwrap();
+impl From<FrameError> for DisconnectReason {
+    fn from(e: FrameError) -> Self {
+        match e {
+            FrameError::Io(e) => e.into(),
+            FrameError::Invalid(reason) => DisconnectReason::Protocol(reason),
+        }
     }
+}
 
-    pub fn read_loop(&mut self, rpc_sender: Sender<RPC>) {
-        loop {
-            let mut buffer = [0u8; 2048];
-            let n = self.stream.read(&mut buffer).unwrap();
-            println!("received {} bytes", n);
-            let b = buffer[0..n].to_vec();
-            let addr = self.stream.peer_addr().unwrap();
-            rpc_sender
-                .send(RPC {
-                    from: addr,
-                    data: b,
-                })
-                .unwrap();
+impl fmt::Display for DisconnectReason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DisconnectReason::Closed => write!(f, "closed by peer"),
+            DisconnectReason::Reset => write!(f, "connection reset"),
+            DisconnectReason::Timeout => write!(f, "timed out"),
+            DisconnectReason::Protocol(reason) => write!(f, "protocol error: {}", reason),
+            DisconnectReason::Shutdown => write!(f, "node shutting down"),
+            DisconnectReason::Error(e) => write!(f, "{}", e),
         }
     }
 }
 
-impl Clone for TcpPeer {
-    fn clone(&self) -> Self {
-        Self {
-            stream: self.stream.try_clone().unwrap(),
+// sent to the server so it can drop a peer, possibly more than once per peer
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PeerEvent {
+    Disconnected(SocketAddr, DisconnectReason),
+}
+
+pub struct TcpPeer {
+    pub stream: TcpStream,
+    pub addr: SocketAddr,
+    pub outgoing: bool,
+    pub codec: FrameCodec,
+    // held for each frame written, shared with clones. Threads send to the same
+    // peer, and a frame may take several writes
+    write_lock: Arc<Mutex<()>>,
+}
+
+impl TcpPeer {
+    pub fn new(stream: TcpStream, outgoing: bool, codec: FrameCodec) -> Result<Self, String> {
+        let addr = stream
+            .peer_addr()
+            .map_err(|e| format!("error: peer has no address: {}", e))?;
+        stream
+            .set_write_timeout(Some(PEER_TIMEOUT))
+            .map_err(|e| format!("error: configuring peer {}: {}", addr, e))?;
+        Ok(Self {
+            stream,
+            addr,
+            outgoing,
+            codec,
+            write_lock: Arc::new(Mutex::new(())),
+        })
+    }
+
+    // another handle to the same connection, for the reading thread
+    pub fn try_clone(&self) -> Result<Self, String> {
+        let stream = self
+            .stream
+            .try_clone()
+            .map_err(|e| format!("error: cloning peer {}: {}", self.addr, e))?;
+        Ok(Self {
+            stream,
+            addr: self.addr,
             outgoing: self.outgoing,
-        }
+            codec: self.codec,
+            write_lock: self.write_lock.clone(),
+        })
+    }
+
+    pub fn send(&self, message: Message) -> Result<(), FrameError> {
+        // a thread that panicked mid frame has already left the stream out of step
+        let _guard = self.write_lock.lock().unwrap_or_else(|e| e.into_inner());
+        self.codec
+            .write_frame(&mut &self.stream, message.header(), message.data())
+    }
+
+    // reads one frame at a time until the peer goes away, then reports why on
+    // event_sender. A frame that is too large or cut short leaves the stream out
+    // of step, so the peer is dropped
+    pub fn read_loop(&mut self, rpc_sender: Sender<RPC>, event_sender: Sender<PeerEvent>) {
+        let reason = loop {
+            // waiting for a frame to start has no timeout, only reading it does
+            let _ = self.stream.set_read_timeout(None);
+            match self.stream.peek(&mut [0u8]) {
+                Ok(0) => break DisconnectReason::Closed,
+                Ok(_) => {}
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => break e.into(),
+            }
+            if let Err(e) = self.stream.set_read_timeout(Some(PEER_TIMEOUT)) {
+                break e.into();
+            }
+
+            let (message_type, payload) = match self.codec.read_frame(&mut self.stream) {
+                Ok(Some(frame)) => frame,
+                Ok(None) => break DisconnectReason::Closed,
+                Err(e) => break e.into(),
+            };
+            let mut data = Vec::with_capacity(payload.len() + 1);
+            data.push(message_type);
+            data.extend(payload);
+            let rpc = RPC {
+                from: self.addr,
+                data,
+            };
+            if rpc_sender.send(rpc).is_err() {
+                break DisconnectReason::Shutdown;
+            }
+        };
+        let _ = self.stream.shutdown(Shutdown::Both);
+        let _ = event_sender.send(PeerEvent::Disconnected(self.addr, reason));
     }
 }
 
 pub struct TCPTransport {
     pub listen_addr: String,
     pub peer_sender: Sender<TcpPeer>,
+    pub codec: FrameCodec,
 }
 
 impl TCPTransport {
-    pub fn new(listen_addr: String, peer_sender: Sender<TcpPeer>) -> Self {
+    pub fn new(listen_addr: String, peer_sender: Sender<TcpPeer>, codec: FrameCodec) -> Self {
         TCPTransport {
-            listen_addr: listen_addr,
-            peer_sender: peer_sender,
+            listen_addr,
+            peer_sender,
+            codec,
         }
     }
 
-    pub fn start(&self) {
-        let listener = TcpListener::bind("localhost:".to_string() + &self.listen_addr).unwrap();
+    pub fn start(&self) -> Result<(), String> {
+        let addr = "localhost:".to_string() + &self.listen_addr;
+        let listener =
+            TcpListener::bind(&addr).map_err(|e| format!("error: listening on {}: {}", addr, e))?;
         let peer_sender = self.peer_sender.clone();
+        let codec = self.codec;
 
         thread::spawn(move || {
             loop {
                 // listen for new incoming connections
-                let (socket, _addr) = listener.accept().unwrap();
-                let tcp_peer = TcpPeer::new(socket, false);
-                println!("new connection from {:?}", _addr);
-
-                peer_sender.send(tcp_peer).unwrap();
+                let tcp_peer = listener
+                    .accept()
+                    .map_err(|e| format!("error: accepting connection: {}", e))
+                    .and_then(|(socket, _addr)| TcpPeer::new(socket, false, codec));
+                match tcp_peer {
+                    Ok(tcp_peer) => {
+                        println!("new connection from {:?}", tcp_peer.addr);
+                        if peer_sender.send(tcp_peer).is_err() {
+                            return;
+                        }
+                    }
+                    Err(err) => println!("{}", err),
+                }
             }
         });
+        Ok(())
     }
 
     // fn read_loop(&self, socket: Arc<TcpStream>) {
@@ -88,3 +206,104 @@ impl TCPTransport {
     //     }
     // }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::network::rpc::MESSAGE_TYPE_TX;
+    use std::io::Write;
+    use std::sync::mpsc::channel;
+
+    // a connected pair, with the accepted side reading in its own thread
+    fn connect() -> (
+        TcpStream,
+        std::sync::mpsc::Receiver<RPC>,
+        std::sync::mpsc::Receiver<PeerEvent>,
+    ) {
+        connect_with(FrameCodec::new(1024))
+    }
+
+    fn connect_with(
+        codec: FrameCodec,
+    ) -> (
+        TcpStream,
+        std::sync::mpsc::Receiver<RPC>,
+        std::sync::mpsc::Receiver<PeerEvent>,
+    ) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (socket, _) = listener.accept().unwrap();
+        let mut peer = TcpPeer::new(socket, false, codec).unwrap();
+        let (rpc_sender, rpc_receiver) = channel();
+        let (event_sender, event_receiver) = channel();
+        thread::spawn(move || peer.read_loop(rpc_sender, event_sender));
+        (client, rpc_receiver, event_receiver)
+    }
+
+    #[test]
+    fn test_peer_closes() {
+        let (client, rpc_receiver, event_receiver) = connect();
+        let addr = client.local_addr().unwrap();
+        let sender = TcpPeer::new(client, true, FrameCodec::new(1024)).unwrap();
+        sender
+            .send(Message::new(MESSAGE_TYPE_TX, b"tx".to_vec()))
+            .unwrap();
+        let rpc = rpc_receiver.recv().unwrap();
+        assert_eq!(rpc.data, vec![MESSAGE_TYPE_TX, b't', b'x']);
+        assert_eq!(rpc.from, addr);
+
+        drop(sender);
+        assert_eq!(
+            event_receiver.recv().unwrap(),
+            PeerEvent::Disconnected(addr, DisconnectReason::Closed)
+        );
+    }
+
+    #[test]
+    fn test_invalid_frame_disconnects() {
+        let (mut client, rpc_receiver, event_receiver) = connect();
+        let addr = client.local_addr().unwrap();
+        // announces a frame over the 1024 byte limit
+        client.write_all(&[0, 8, 0, 0, MESSAGE_TYPE_TX, 0]).unwrap();
+        match event_receiver.recv().unwrap() {
+            PeerEvent::Disconnected(from, DisconnectReason::Protocol(_)) => assert_eq!(from, addr),
+            event => panic!("unexpected event {:?}", event),
+        }
+        assert!(rpc_receiver.try_recv().is_err());
+    }
+
+    #[test]
+    fn test_concurrent_sends_do_not_interleave() {
+        let codec = FrameCodec::new(1 << 20);
+        let (client, rpc_receiver, _event_receiver) = connect_with(codec);
+        let sender = TcpPeer::new(client, true, codec).unwrap();
+        let threads: Vec<_> = (0..4u8)
+            .map(|i| {
+                let peer = sender.try_clone().unwrap();
+                thread::spawn(move || {
+                    for _ in 0..8 {
+                        peer.send(Message::new(MESSAGE_TYPE_TX, vec![i; 256 * 1024]))
+                            .unwrap();
+                    }
+                })
+            })
+            .collect();
+        for _ in 0..32 {
+            let rpc = rpc_receiver.recv().unwrap();
+            assert_eq!(rpc.data.len(), 256 * 1024 + 1);
+            assert!(rpc.data[1..].iter().all(|b| *b == rpc.data[1]));
+        }
+        for thread in threads {
+            thread.join().unwrap();
+        }
+    }
+
+    #[test]
+    fn test_start_fails_when_address_in_use() {
+        let listener = TcpListener::bind("localhost:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let (peer_sender, _peer_receiver) = channel();
+        let transport = TCPTransport::new(port.to_string(), peer_sender, FrameCodec::new(1024));
+        assert!(transport.start().is_err());
+    }
+}
@@ -1,24 +1,256 @@
-use std::collections::HashMap;
+use std::cmp::Ordering;
+use std::collections::{btree_map, BTreeMap, BTreeSet, HashMap};
+use std::time::{SystemTime, UNIX_EPOCH};
 
-use crate::{core::transaction::Transaction, types::hash::Hash};
+use super::rejection::Rejection;
+use crate::{
+    core::{
+        state::{sender_address, State, StateError},
+        transaction::Transaction,
+    },
+    types::{address::Address, hash::Hash},
+};
+
+pub const DEFAULT_MAX_POOL_SIZE: usize = 10_000;
+pub const DEFAULT_MAX_PER_SENDER: usize = 64;
+// a transaction replaces the sender's pooled one with the same nonce only if its
+// fee rate is higher by more than this, so a nonce can't be resent over and over
+pub const REPLACEMENT_FEE_BUMP_PERCENT: u64 = 10;
+
+// orders pooled transactions from the most to the least worth including: by fee
+// per encoded byte, then the one received first
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Priority {
+    fee: u64,
+    size: u64,
+    first_seen: i64,
+    hash: Hash,
+}
+
+impl Ord for Priority {
+    fn cmp(&self, other: &Self) -> Ordering {
+        // the fee rates are compared without dividing
+        let ours = self.fee as u128 * other.size as u128;
+        let theirs = other.fee as u128 * self.size as u128;
+        theirs
+            .cmp(&ours)
+            .then(self.first_seen.cmp(&other.first_seen))
+            .then(self.hash.cmp(&other.hash))
+    }
+}
+
+impl Priority {
+    fn outbids(&self, replaced: &Priority) -> bool {
+        let ours = self.fee as u128 * replaced.size as u128 * 100;
+        let theirs =
+            replaced.fee as u128 * self.size as u128 * (100 + REPLACEMENT_FEE_BUMP_PERCENT) as u128;
+        ours > theirs
+    }
+}
+
+impl PartialOrd for Priority {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+struct Entry {
+    tx: Transaction,
+    sender: Address,
+    priority: Priority,
+}
+
+// a sender's pooled transactions by nonce. The ones below next_nonce follow the
+// sender's nonce in the chain state without a gap and can execute, the others
+// are queued until the gap before them is filled
+#[derive(Default)]
+struct SenderQueue {
+    nonces: BTreeMap<u64, Hash>,
+    next_nonce: u64,
+}
+
+impl SenderQueue {
+    // moves forward every queued transaction that follows on from the chain nonce
+    fn promote(&mut self, chain_nonce: u64) {
+        self.next_nonce = chain_nonce;
+        while self.nonces.contains_key(&self.next_nonce) {
+            self.next_nonce += 1;
+        }
+    }
+}
 
 pub struct TxPool {
-    transactions: HashMap<Hash, Transaction>,
+    transactions: HashMap<Hash, Entry>,
+    // every pooled transaction, the best first
+    by_priority: BTreeSet<Priority>,
+    senders: HashMap<Address, SenderQueue>,
+    // beyond it the cheapest transactions are evicted
+    max_size: usize,
+    // how many transactions, executable or queued, one sender may have pooled
+    max_per_sender: usize,
 }
 
 impl TxPool {
-    pub fn new() -> Self {
+    pub fn new(max_size: usize, max_per_sender: usize) -> Self {
         TxPool {
             transactions: HashMap::new(),
+            by_priority: BTreeSet::new(),
+            senders: HashMap::new(),
+            max_size,
+            max_per_sender,
         }
     }
 
-    pub fn add(&mut self, mut tx: Transaction) {
-        self.transactions.insert(tx.hash(), tx);
+    // admits a transaction whose nonce the sender hasn't used yet, if the sender's
+    // balance covers it along with the sender's pooled transactions before it.
+    // One that doesn't directly follow the sender's others is queued behind the
+    // gap. When the pool is full it has to pay more than the cheapest one, which is evicted.
+    // A pooled nonce is only taken over by a transaction with a fee rate
+    // REPLACEMENT_FEE_BUMP_PERCENT higher, if the sender can still pay for all of its transactions
+    pub fn add(&mut self, mut tx: Transaction, state: &State) -> Result<(), Rejection> {
+        if self.has(&mut tx) {
+            return Err(Rejection::Duplicate);
+        }
+        let sender = sender_address(&tx).map_err(Rejection::State)?;
+        let nonce = tx.data.nonce;
+        let chain_nonce = state.nonce_of(&sender);
+        if nonce < chain_nonce {
+            return Err(Rejection::State(StateError::NonceTooLow {
+                expected: chain_nonce,
+                got: nonce,
+            }));
+        }
+        self.update_sender(&sender, state);
+        tx.set_first_seen(now_millis());
+        let priority = priority_of(&mut tx);
+        let replaced = self
+            .senders
+            .get(&sender)
+            .and_then(|queue| queue.nonces.get(&nonce))
+            .map(|hash| self.transactions[hash].priority);
+        let mut cost = tx.cost();
+        if let Some(queue) = self.senders.get(&sender) {
+            match replaced {
+                Some(replaced) if !priority.outbids(&replaced) => {
+                    return Err(Rejection::NonceInUse(nonce))
+                }
+                // the replaced transaction's successors have to stay affordable too
+                Some(_) => {
+                    for (_, hash) in queue.nonces.iter().filter(|(n, _)| **n != nonce) {
+                        cost = cost.saturating_add(self.transactions[hash].tx.cost());
+                    }
+                }
+                None if queue.nonces.len() >= self.max_per_sender => {
+                    return Err(Rejection::SenderLimit(self.max_per_sender))
+                }
+                None => {
+                    for (_, hash) in queue.nonces.range(..nonce) {
+                        cost = cost.saturating_add(self.transactions[hash].tx.cost());
+                    }
+                }
+            }
+        }
+        let balance = state.balance_of(&sender);
+        if cost > balance {
+            return Err(Rejection::State(StateError::InsufficientBalance {
+                balance,
+                amount: cost,
+            }));
+        }
+
+        // a replacement doesn't grow the pool
+        if replaced.is_none()
+            && self.transactions.len() >= self.max_size
+            && self
+                .by_priority
+                .last()
+                .is_none_or(|cheapest| priority > *cheapest)
+        {
+            return Err(Rejection::Underpriced);
+        }
+        if let Some(replaced) = replaced {
+            self.take(&replaced.hash);
+        }
+        self.insert(tx, sender, priority);
+        // filling a gap may leave the queued transactions after it unaffordable
+        self.update_sender(&sender, state);
+        self.evict_overflow();
+        Ok(())
+    }
+
+    // puts back transactions from blocks dropped by a reorg. They skip the
+    // limits of add, only ones the chain has already executed are discarded
+    pub fn add_orphaned(&mut self, transactions: Vec<Transaction>, state: &State) {
+        for mut tx in transactions {
+            let sender = match sender_address(&tx) {
+                Ok(sender) => sender,
+                Err(_) => continue,
+            };
+            let taken = self
+                .senders
+                .get(&sender)
+                .is_some_and(|queue| queue.nonces.contains_key(&tx.data.nonce));
+            if taken || self.has(&mut tx) || tx.data.nonce < state.nonce_of(&sender) {
+                continue;
+            }
+            tx.set_first_seen(now_millis());
+            let priority = priority_of(&mut tx);
+            self.insert(tx, sender, priority);
+        }
+        self.prune(state);
+        self.evict_overflow();
+    }
+
+    // called after each new block: drops the transactions whose nonce the
+    // chain has used since or that the sender can no longer afford, and
+    // promotes the ones the block unblocked
+    pub fn prune(&mut self, state: &State) {
+        let senders: Vec<Address> = self.senders.keys().copied().collect();
+        for sender in senders {
+            self.update_sender(&sender, state);
+        }
+    }
+
+    // drops a transaction once a block includes it
+    pub fn remove(&mut self, hash: &Hash) -> Option<Transaction> {
+        self.take(hash).map(|entry| entry.tx)
+    }
+
+    // the executable transactions to put in a block, the best first, produced
+    // as they're taken. A sender's transactions always come in nonce order, so
+    // a cheap one may hold back the better paying ones after it. Queued ones are left out
+    pub fn pending(&self) -> Pending<'_> {
+        let mut queues: HashMap<&Address, _> = self
+            .senders
+            .iter()
+            .map(|(sender, queue)| (sender, queue.nonces.range(..queue.next_nonce)))
+            .collect();
+        // each sender's lowest nonce is ready, the rest wait behind it
+        let mut ready = BTreeMap::new();
+        for (sender, queue) in queues.iter_mut() {
+            if let Some((_, hash)) = queue.next() {
+                ready.insert(self.transactions[hash].priority, *sender);
+            }
+        }
+        Pending {
+            pool: self,
+            queues,
+            ready,
+        }
+    }
+
+    // drops a transaction that failed against the chain state. The sender's
+    // later transactions are queued again until the nonce is sent anew
+    pub fn evict(&mut self, hash: &Hash) -> Option<Transaction> {
+        let evicted = self.take(hash)?;
+        if let Some(queue) = self.senders.get_mut(&evicted.sender) {
+            queue.next_nonce = queue.next_nonce.min(evicted.tx.data.nonce);
+        }
+        Some(evicted.tx)
     }
 
     pub fn transactions(&self) -> Vec<&Transaction> {
-        self.transactions.values().collect()
+        self.transactions.values().map(|entry| &entry.tx).collect()
     }
 
     pub fn has(&self, tx: &mut Transaction) -> bool {
@@ -28,4 +260,346 @@ impl TxPool {
     pub fn len(&self) -> usize {
         self.transactions.len()
     }
+
+    pub fn is_empty(&self) -> bool {
+        self.transactions.is_empty()
+    }
+
+    fn insert(&mut self, tx: Transaction, sender: Address, priority: Priority) {
+        self.by_priority.insert(priority);
+        self.senders
+            .entry(sender)
+            .or_default()
+            .nonces
+            .insert(tx.data.nonce, priority.hash);
+        let entry = Entry {
+            tx,
+            sender,
+            priority,
+        };
+        self.transactions.insert(priority.hash, entry);
+    }
+
+    fn take(&mut self, hash: &Hash) -> Option<Entry> {
+        let entry = self.transactions.remove(hash)?;
+        self.by_priority.remove(&entry.priority);
+        if let Some(queue) = self.senders.get_mut(&entry.sender) {
+            queue.nonces.remove(&entry.tx.data.nonce);
+            if queue.nonces.is_empty() {
+                self.senders.remove(&entry.sender);
+            }
+        }
+        Some(entry)
+    }
+
+    // drops the sender's transactions below the chain nonce and the ones from
+    // where the balance runs out, then promotes the ones following on from the
+    // chain nonce
+    fn update_sender(&mut self, sender: &Address, state: &State) {
+        let queue = match self.senders.get(sender) {
+            Some(queue) => queue,
+            None => return,
+        };
+        let chain_nonce = state.nonce_of(sender);
+        let balance = state.balance_of(sender);
+        let mut cost: u64 = 0;
+        let mut dropped = vec![];
+        for (nonce, hash) in &queue.nonces {
+            if *nonce >= chain_nonce {
+                cost = cost.saturating_add(self.transactions[hash].tx.cost());
+            }
+            if *nonce < chain_nonce || cost > balance {
+                dropped.push(*hash);
+            }
+        }
+        for hash in dropped {
+            self.take(&hash);
+        }
+        if let Some(queue) = self.senders.get_mut(sender) {
+            queue.promote(chain_nonce);
+        }
+    }
+
+    // evicts until the pool fits. The sender of the cheapest transaction gives
+    // up its highest nonce, so none of its others is left queued behind a gap
+    fn evict_overflow(&mut self) {
+        while self.transactions.len() > self.max_size {
+            let sender = match self.by_priority.last() {
+                Some(priority) => self.transactions[&priority.hash].sender,
+                None => return,
+            };
+            let last = match self.senders[&sender].nonces.last_key_value() {
+                Some((_, hash)) => *hash,
+                None => return,
+            };
+            self.evict(&last);
+        }
+    }
+}
+
+pub struct Pending<'a> {
+    pool: &'a TxPool,
+    // each sender's executable transactions not taken yet
+    queues: HashMap<&'a Address, btree_map::Range<'a, u64, Hash>>,
+    // the next transaction of each sender, the best first
+    ready: BTreeMap<Priority, &'a Address>,
+}
+
+impl<'a> Iterator for Pending<'a> {
+    type Item = &'a Transaction;
+
+    fn next(&mut self) -> Option<&'a Transaction> {
+        let (priority, sender) = self.ready.pop_first()?;
+        if let Some((_, hash)) = self.queues.get_mut(sender).unwrap().next() {
+            self.ready
+                .insert(self.pool.transactions[hash].priority, sender);
+        }
+        Some(&self.pool.transactions[&priority.hash].tx)
+    }
+}
+
+fn priority_of(tx: &mut Transaction) -> Priority {
+    Priority {
+        fee: tx.data.fee,
+        size: tx.encode().len() as u64,
+        first_seen: tx.first_seen().unwrap_or_default(),
+        hash: tx.hash(),
+    }
+}
+
+fn now_millis() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_millis() as i64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::state::StateError;
+    use crate::crypto::keypair::KeyPair;
+
+    fn signed_transaction(nonce: u64) -> Transaction {
+        let mut tx = Transaction::new([0; 20], 5, nonce, 0);
+        tx.sign(&KeyPair::new(0));
+        tx
+    }
+
+    fn funded_state() -> State {
+        let mut state = State::new();
+        for key in 0..3 {
+            state.credit(KeyPair::new(key).address(), 1000);
+        }
+        state
+    }
+
+    #[test]
+    fn test_add_enforces_nonces() {
+        let mut pool = TxPool::new(DEFAULT_MAX_POOL_SIZE, DEFAULT_MAX_PER_SENDER);
+        let state = funded_state();
+
+        assert_eq!(pool.add(signed_transaction(0), &state), Ok(()));
+        assert_eq!(pool.add(signed_transaction(1), &state), Ok(()));
+        let mut other = Transaction::new([1; 20], 5, 1, 0);
+        other.sign(&KeyPair::new(0));
+        assert_eq!(pool.add(other, &state), Err(Rejection::NonceInUse(1)));
+        assert_eq!(pool.len(), 2);
+
+        let mut state = State::new();
+        state.credit(KeyPair::new(0).address(), 10);
+        state.apply_transactions(&[signed_transaction(0)]).unwrap();
+        let mut pool = TxPool::new(DEFAULT_MAX_POOL_SIZE, DEFAULT_MAX_PER_SENDER);
+        assert_eq!(
+            pool.add(signed_transaction(0), &state),
+            Err(Rejection::State(StateError::NonceTooLow {
+                expected: 1,
+                got: 0
+            }))
+        );
+    }
+
+    #[test]
+    fn test_add_checks_balance() {
+        let mut pool = TxPool::new(DEFAULT_MAX_POOL_SIZE, DEFAULT_MAX_PER_SENDER);
+        let key_pair = KeyPair::new(0);
+        let mut state = State::new();
+        state.credit(key_pair.address(), 12);
+
+        pool.add(signed_transaction(0), &state).unwrap();
+        pool.add(signed_transaction(2), &state).unwrap();
+        assert_eq!(
+            pool.add(signed_transaction(3), &state),
+            Err(Rejection::State(StateError::InsufficientBalance {
+                balance: 12,
+                amount: 15
+            }))
+        );
+        // filling the gap leaves 2 unaffordable
+        pool.add(signed_transaction(1), &state).unwrap();
+        assert_eq!(pool.len(), 2);
+        assert!(!pool.has(&mut signed_transaction(2)));
+
+        // a fee the sender can't pay doesn't get it into the pool
+        assert!(matches!(
+            pool.add(with_fee(3, 0, 1000), &state),
+            Err(Rejection::State(StateError::InsufficientBalance { .. }))
+        ));
+
+        // a block spending from the account elsewhere leaves 1 unaffordable
+        let mut spend = Transaction::new([2; 20], 8, 0, 0);
+        spend.sign(&key_pair);
+        state.apply_transactions(&[spend]).unwrap();
+        pool.prune(&state);
+        assert_eq!(pool.len(), 0);
+    }
+
+    fn nonces<'a>(transactions: impl Iterator<Item = &'a Transaction>) -> Vec<u64> {
+        transactions.map(|tx| tx.data.nonce).collect()
+    }
+
+    #[test]
+    fn test_queued_transactions() {
+        let mut pool = TxPool::new(DEFAULT_MAX_POOL_SIZE, 4);
+        let key_pair = KeyPair::new(0);
+        let mut state = State::new();
+        state.credit(key_pair.address(), 100);
+
+        pool.add(signed_transaction(0), &state).unwrap();
+        pool.add(signed_transaction(2), &state).unwrap();
+        pool.add(signed_transaction(3), &state).unwrap();
+        // 2 and 3 wait for the gap to fill
+        assert_eq!(nonces(pool.pending()), vec![0]);
+        pool.add(signed_transaction(1), &state).unwrap();
+        assert_eq!(nonces(pool.pending()), vec![0, 1, 2, 3]);
+
+        assert_eq!(
+            pool.add(signed_transaction(5), &state),
+            Err(Rejection::SenderLimit(4))
+        );
+        let mut other_sender = Transaction::new([0; 20], 5, 0, 0);
+        other_sender.sign(&KeyPair::new(1));
+        state.credit(KeyPair::new(1).address(), 5);
+        assert_eq!(pool.add(other_sender, &state), Ok(()));
+
+        // a block including 0 and 1 makes them stale
+        state
+            .apply_transactions(&[signed_transaction(0), signed_transaction(1)])
+            .unwrap();
+        pool.remove(&signed_transaction(0).hash());
+        pool.prune(&state);
+        assert_eq!(pool.len(), 3);
+        assert!(!pool.has(&mut signed_transaction(1)));
+        pool.add(signed_transaction(5), &state).unwrap();
+        assert_eq!(pool.pending().count(), 3);
+    }
+
+    #[test]
+    fn test_prune_promotes() {
+        let mut pool = TxPool::new(DEFAULT_MAX_POOL_SIZE, DEFAULT_MAX_PER_SENDER);
+        let mut state = State::new();
+        state.credit(KeyPair::new(0).address(), 100);
+        pool.add(signed_transaction(1), &state).unwrap();
+        assert_eq!(pool.pending().count(), 0);
+
+        // a block from elsewhere used nonce 0
+        state.apply_transactions(&[signed_transaction(0)]).unwrap();
+        pool.prune(&state);
+        assert_eq!(nonces(pool.pending()), vec![1]);
+    }
+
+    #[test]
+    fn test_add_orphaned() {
+        let mut pool = TxPool::new(DEFAULT_MAX_POOL_SIZE, DEFAULT_MAX_PER_SENDER);
+        let key_pair = KeyPair::new(0);
+        let mut state = State::new();
+        state.credit(key_pair.address(), 20);
+        state.apply_transactions(&[signed_transaction(0)]).unwrap();
+
+        pool.add_orphaned(vec![signed_transaction(0), signed_transaction(1)], &state);
+        assert_eq!(pool.len(), 1);
+        assert!(pool.has(&mut signed_transaction(1)));
+        assert_eq!(pool.add(signed_transaction(2), &state), Ok(()));
+    }
+
+    fn with_fee(key: u64, nonce: u64, fee: u64) -> Transaction {
+        let mut tx = Transaction::new([0; 20], 5, nonce, 0).with_fee(fee);
+        tx.sign(&KeyPair::new(key));
+        tx
+    }
+
+    fn fees<'a>(transactions: impl Iterator<Item = &'a Transaction>) -> Vec<u64> {
+        transactions.map(|tx| tx.data.fee).collect()
+    }
+
+    #[test]
+    fn test_pending_order() {
+        let mut pool = TxPool::new(DEFAULT_MAX_POOL_SIZE, DEFAULT_MAX_PER_SENDER);
+        let state = funded_state();
+        pool.add(with_fee(0, 0, 10), &state).unwrap();
+        pool.add(with_fee(1, 0, 30), &state).unwrap();
+        pool.add(with_fee(2, 0, 20), &state).unwrap();
+        assert_eq!(fees(pool.pending()), vec![30, 20, 10]);
+        assert_eq!(fees(pool.pending().take(2)), vec![30, 20]);
+
+        // the better paying transaction waits for the sender's earlier one
+        pool.add(with_fee(0, 1, 40), &state).unwrap();
+        assert_eq!(fees(pool.pending()), vec![30, 20, 10, 40]);
+        pool.add(with_fee(2, 1, 25), &state).unwrap();
+        assert_eq!(fees(pool.pending()), vec![30, 20, 25, 10, 40]);
+    }
+
+    #[test]
+    fn test_eviction() {
+        let mut pool = TxPool::new(3, DEFAULT_MAX_PER_SENDER);
+        let state = funded_state();
+        pool.add(with_fee(0, 0, 10), &state).unwrap();
+        pool.add(with_fee(0, 1, 50), &state).unwrap();
+        pool.add(with_fee(1, 0, 20), &state).unwrap();
+
+        assert_eq!(
+            pool.add(with_fee(2, 0, 5), &state),
+            Err(Rejection::Underpriced)
+        );
+        // the sender of the cheapest transaction loses its last one, which
+        // leaves nothing queued behind a gap
+        pool.add(with_fee(2, 0, 30), &state).unwrap();
+        assert_eq!(fees(pool.pending()), vec![30, 20, 10]);
+        assert!(!pool.has(&mut with_fee(0, 1, 50)));
+        assert!(pool.has(&mut with_fee(0, 0, 10)));
+
+        pool.add(with_fee(1, 1, 40), &state).unwrap();
+        assert_eq!(pool.len(), 3);
+        assert_eq!(fees(pool.pending()), vec![30, 20, 40]);
+    }
+
+    #[test]
+    fn test_replacement() {
+        let mut pool = TxPool::new(2, DEFAULT_MAX_PER_SENDER);
+        let mut state = State::new();
+        state.credit(KeyPair::new(0).address(), 100);
+        pool.add(with_fee(0, 0, 10), &state).unwrap();
+        pool.add(with_fee(0, 1, 10), &state).unwrap();
+
+        // the fee rate has to beat the pooled one by the margin
+        assert_eq!(
+            pool.add(with_fee(0, 0, 11), &state),
+            Err(Rejection::NonceInUse(0))
+        );
+        pool.add(with_fee(0, 0, 12), &state).unwrap();
+        assert_eq!(pool.len(), 2);
+        assert!(!pool.has(&mut with_fee(0, 0, 10)));
+        assert_eq!(fees(pool.pending()), vec![12, 10]);
+
+        // and the sender still has to afford all of its transactions
+        assert_eq!(
+            pool.add(with_fee(0, 0, 90), &state),
+            Err(Rejection::State(StateError::InsufficientBalance {
+                balance: 100,
+                amount: 110
+            }))
+        );
+        pool.add(with_fee(0, 0, 80), &state).unwrap();
+        assert_eq!(fees(pool.pending()), vec![80, 10]);
+    }
 }
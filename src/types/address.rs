@@ -1 +1,100 @@
+use super::hash::Hash;
+use secp256k1::PublicKey;
+
 pub type Address = [u8; 20];
+
+// the address of a secp256k1 key is the last 20 bytes of the sha256 of its compressed public key
+pub fn address_from_public_key(public_key: &PublicKey) -> Address {
+    address_from_key_bytes(&public_key.serialize())
+}
+
+// the same derivation for keys of any scheme, given in their encoded form
+pub fn address_from_key_bytes(key: &[u8]) -> Address {
+    let hash = Hash::digest(key);
+    let mut address: Address = [0; 20];
+    address.copy_from_slice(&hash.as_bytes()[12..]);
+    address
+}
+
+// hex with a 0x prefix. Letters are uppercased where the matching nibble of the
+// sha256 of the lowercase hex is 8 or more, so typos are caught on decoding
+pub fn encode_address(address: &Address) -> String {
+    let lower = hex::encode(address);
+    let checksum = Hash::digest(lower.as_bytes());
+    let encoded: String = lower
+        .chars()
+        .enumerate()
+        .map(|(i, c)| {
+            if checksum_nibble(&checksum, i) >= 8 {
+                c.to_ascii_uppercase()
+            } else {
+                c
+            }
+        })
+        .collect();
+    format!("0x{}", encoded)
+}
+
+// all lowercase or all uppercase input is accepted without a checksum
+pub fn decode_address(s: &str) -> Result<Address, String> {
+    let s = s.strip_prefix("0x").unwrap_or(s);
+    let bytes = hex::decode(s).map_err(|_| "error: address is not valid hex".to_string())?;
+    if bytes.len() != 20 {
+        return Err(format!(
+            "error: address has {} bytes => expected 20",
+            bytes.len()
+        ));
+    }
+    let mut address: Address = [0; 20];
+    address.copy_from_slice(&bytes);
+
+    let mixed_case =
+        s.chars().any(|c| c.is_ascii_lowercase()) && s.chars().any(|c| c.is_ascii_uppercase());
+    if mixed_case && encode_address(&address)[2..] != *s {
+        return Err("error: invalid address checksum".to_string());
+    }
+    Ok(address)
+}
+
+fn checksum_nibble(checksum: &Hash, i: usize) -> u8 {
+    let byte = checksum.as_bytes()[i / 2];
+    if i.is_multiple_of(2) {
+        byte >> 4
+    } else {
+        byte & 0x0f
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::keypair::KeyPair;
+
+    #[test]
+    fn test_encode_decode() {
+        let address = KeyPair::new(0).address();
+        let encoded = encode_address(&address);
+        assert_eq!(encoded.len(), 42);
+        assert!(encoded.starts_with("0x"));
+        assert_eq!(decode_address(&encoded), Ok(address));
+        assert_eq!(decode_address(&encoded.to_lowercase()), Ok(address));
+        assert_eq!(decode_address(&encoded[2..].to_uppercase()), Ok(address));
+
+        // flipping the case of one letter breaks the checksum
+        let pos = encoded[2..]
+            .find(|c: char| c.is_ascii_alphabetic())
+            .unwrap()
+            + 2;
+        let mut flipped: Vec<char> = encoded.chars().collect();
+        flipped[pos] = if flipped[pos].is_ascii_uppercase() {
+            flipped[pos].to_ascii_lowercase()
+        } else {
+            flipped[pos].to_ascii_uppercase()
+        };
+        let flipped: String = flipped.into_iter().collect();
+        assert!(decode_address(&flipped).is_err());
+
+        assert!(decode_address("0x1234").is_err());
+        assert!(decode_address("zz").is_err());
+    }
+}
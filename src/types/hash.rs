@@ -1 +1,107 @@
-pub type Hash = String;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use sha2::{Digest, Sha256};
+use std::fmt;
+use std::str::FromStr;
+
+// a sha256 hash, written as 64 hex characters in text and JSON
+#[derive(Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Default)]
+pub struct Hash(pub [u8; 32]);
+
+impl Hash {
+    // used as the previous block hash of the genesis block
+    pub const fn zero() -> Self {
+        Hash([0; 32])
+    }
+
+    pub fn is_zero(&self) -> bool {
+        self.0 == [0; 32]
+    }
+
+    pub fn digest(data: &[u8]) -> Self {
+        let mut hash = [0; 32];
+        hash.copy_from_slice(&Sha256::digest(data));
+        Hash(hash)
+    }
+
+    pub fn from_slice(bytes: &[u8]) -> Result<Self, String> {
+        if bytes.len() != 32 {
+            return Err(format!(
+                "error: hash must be 32 bytes, given {}",
+                bytes.len()
+            ));
+        }
+        let mut hash = [0; 32];
+        hash.copy_from_slice(bytes);
+        Ok(Hash(hash))
+    }
+
+    pub fn as_bytes(&self) -> &[u8; 32] {
+        &self.0
+    }
+}
+
+impl fmt::Display for Hash {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", hex::encode(self.0))
+    }
+}
+
+impl fmt::Debug for Hash {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Hash({})", self)
+    }
+}
+
+impl FromStr for Hash {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match hex::decode(s) {
+            Ok(bytes) => Self::from_slice(&bytes),
+            Err(_) => Err(format!("error: invalid hex hash {}", s)),
+        }
+    }
+}
+
+impl Serialize for Hash {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.to_string())
+    }
+}
+
+impl<'de> Deserialize<'de> for Hash {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        Hash::from_str(&s).map_err(serde::de::Error::custom)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_hex_round_trip() {
+        let hash = Hash::digest(b"hello");
+        assert_eq!(
+            hash.to_string(),
+            "2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824"
+        );
+        assert_eq!(Hash::from_str(&hash.to_string()), Ok(hash));
+
+        assert!(Hash::from_str("invalid hash").is_err());
+        assert!(Hash::from_str("abcd").is_err());
+        assert!(Hash::zero().is_zero());
+        assert!(!hash.is_zero());
+    }
+
+    #[test]
+    fn test_serde() {
+        let hash = Hash::digest(b"hello");
+        let json = serde_json::to_string(&hash).unwrap();
+        assert_eq!(json, format!("\"{}\"", hash));
+        assert_eq!(serde_json::from_str::<Hash>(&json).unwrap(), hash);
+
+        assert!(serde_json::from_str::<Hash>("\"\"").is_err());
+    }
+}